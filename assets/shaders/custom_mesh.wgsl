@@ -1,33 +1,7 @@
 // Import the standard 2d mesh uniforms and set their bind groups
 #import bevy_pbr::mesh_bindings
 #import bevy_pbr::mesh_view_bindings
-
-@group(2) @binding(0)
-var<uniform> view_mat: mat4x4<f32>;
-
-@group(2) @binding(1)
-var<uniform> projection_mat: mat4x4<f32>;
-
-@group(2) @binding(2)
-var diff_tex: texture_2d<f32>;
-
-@group(2) @binding(3)
-var diff_tex_sampler: sampler;
-
-@group(2) @binding(4)
-var spec_tex: texture_2d<f32>;
-
-@group(2) @binding(5)
-var spec_tex_sampler: sampler;
-
-@group(2) @binding(6)
-var emission_tex: texture_2d<f32>;
-
-@group(2) @binding(7)
-var emission_tex_sampler: sampler;
-
-@group(2) @binding(8)
-var<uniform> view_pos: vec3<f32>;
+#import custom_view::limits
 
 struct DirLight {
     direction: vec3<f32>,
@@ -36,8 +10,9 @@ struct DirLight {
     specular: vec4<f32>,
 };
 
-@group(2) @binding(9)
-var<uniform> dir_light: DirLight;
+// The array size needs to be kept in sync with MAX_DIRECTIONAL_LIGHTS
+@group(2) @binding(0)
+var<uniform> dir_lights: array<DirLight, 4u>;
 
 struct PointLight {
     position: vec3<f32>,
@@ -45,14 +20,21 @@ struct PointLight {
     constant: f32,
     lin: f32,
     quadratic: f32,
+    // Layer of the cube shadow map array, -1 when the light doesn't cast shadows
+    shadow_index: i32,
 
     ambient: vec4<f32>,
     diffuse: vec4<f32>,
     specular: vec4<f32>,
 };
 
-@group(2) @binding(10)
-var<uniform> point_l: array<PointLight, 4>;
+#ifdef NO_STORAGE_BUFFERS_SUPPORT
+@group(2) @binding(1)
+var<uniform> point_l: array<PointLight, MAX_UNIFORM_POINT_LIGHTS>;
+#else
+@group(2) @binding(1)
+var<storage> point_l: array<PointLight>;
+#endif
 
 // The props need to be kept in the same order as the binding
 struct Spotlight {
@@ -66,10 +48,116 @@ struct Spotlight {
     constant: f32,
     lin: f32,
     quadratic: f32,
+    // Layer of the shadow map array, -1 when the light doesn't cast shadows
+    shadow_index: i32,
+};
+
+// The array size needs to be kept in sync with MAX_SPOTLIGHTS
+@group(2) @binding(2)
+var<uniform> spotlights: array<Spotlight, 16u>;
+
+struct LightCounts {
+    directional_count: u32,
+    point_count: u32,
+    spot_count: u32,
+};
+
+@group(2) @binding(3)
+var<uniform> light_counts: LightCounts;
+
+@group(2) @binding(4)
+var dir_shadow_map: texture_depth_2d;
+
+@group(2) @binding(5)
+var shadow_sampler: sampler_comparison;
+
+struct DirShadow {
+    light_space: mat4x4<f32>,
+    depth_bias: f32,
+    filter_size: u32,
+    light_index: u32,
+    enabled: u32,
+};
+
+@group(2) @binding(6)
+var<uniform> dir_shadow: DirShadow;
+
+#ifdef NO_CUBE_ARRAY_TEXTURES_SUPPORT
+@group(2) @binding(7)
+var point_shadow_maps: texture_depth_cube;
+#else
+@group(2) @binding(7)
+var point_shadow_maps: texture_depth_cube_array;
+#endif
+
+struct PointShadow {
+    depth_bias: f32,
+    far: f32,
+};
+
+@group(2) @binding(8)
+var<uniform> point_shadow: PointShadow;
+
+@group(2) @binding(9)
+var spotlight_shadow_maps: texture_depth_2d_array;
+
+// The array size needs to be kept in sync with MAX_SPOTLIGHT_SHADOWS
+struct SpotlightShadow {
+    light_spaces: array<mat4x4<f32>, 8u>,
+    depth_bias: f32,
+    softness: f32,
 };
 
-@group(2) @binding(11)
-var<uniform> spotlight: Spotlight;
+@group(2) @binding(10)
+var<uniform> spotlight_shadow: SpotlightShadow;
+
+// The material textures are bound separately from the per view light bindings above
+@group(3) @binding(0)
+var diff_tex: texture_2d<f32>;
+
+@group(3) @binding(1)
+var diff_tex_sampler: sampler;
+
+@group(3) @binding(2)
+var spec_tex: texture_2d<f32>;
+
+@group(3) @binding(3)
+var spec_tex_sampler: sampler;
+
+@group(3) @binding(4)
+var emission_tex: texture_2d<f32>;
+
+@group(3) @binding(5)
+var emission_tex_sampler: sampler;
+
+struct Emission {
+    uv_offset: vec2<f32>,
+    strength: f32,
+    // Only emit where the specular map is black
+    mask_by_specular: u32,
+};
+@group(3) @binding(6)
+var<uniform> emission: Emission;
+
+// Sample offsets spread over the unit disk, used to soften the spotlight shadow edges
+var<private> poisson_disk: array<vec2<f32>, 16u> = array<vec2<f32>, 16u>(
+    vec2<f32>(-0.94201624, -0.39906216),
+    vec2<f32>(0.94558609, -0.76890725),
+    vec2<f32>(-0.094184101, -0.92938870),
+    vec2<f32>(0.34495938, 0.29387760),
+    vec2<f32>(-0.91588581, 0.45771432),
+    vec2<f32>(-0.81544232, -0.87912464),
+    vec2<f32>(-0.38277543, 0.27676845),
+    vec2<f32>(0.97484398, 0.75648379),
+    vec2<f32>(0.44323325, -0.97511554),
+    vec2<f32>(0.53742981, -0.47373420),
+    vec2<f32>(-0.26496911, -0.41893023),
+    vec2<f32>(0.79197514, 0.19090188),
+    vec2<f32>(-0.24188840, 0.99706507),
+    vec2<f32>(-0.81409955, 0.91437590),
+    vec2<f32>(0.19984126, 0.78641367),
+    vec2<f32>(0.14383161, -0.14100790),
+);
 
 struct InstanceInput {
     @location(3) model_mat_0: vec4<f32>,
@@ -79,8 +167,13 @@ struct InstanceInput {
     @location(7) normal_mat_0: vec4<f32>,
     @location(8) normal_mat_1: vec4<f32>,
     @location(9) normal_mat_2: vec4<f32>,
-    @location(10) normal_mat_3: vec4<f32>,
+    @location(10) emission: f32,
     @location(11) shininess: f32,
+    @location(12) highlight: f32,
+    @location(13) diffuse_tint: vec4<f32>,
+    @location(14) specular_tint: vec4<f32>,
+    // Offset in xy, scale in zw
+    @location(15) uv_transform: vec4<f32>,
 }
 
 // NOTE: Bindings must come before functions that use them!
@@ -101,6 +194,10 @@ struct VertexOutput {
     @location(2) uv: vec2<f32>,
     @location(3) frag_pos: vec3<f32>,
     @location(4) shininess: f32,
+    @location(5) highlight: f32,
+    @location(6) emission: f32,
+    @location(7) diffuse_tint: vec4<f32>,
+    @location(8) specular_tint: vec4<f32>,
 };
 
 fn rotate_coords(coords: vec3<f32>, degrees: f32) -> vec3<f32> {
@@ -133,10 +230,14 @@ fn vertex(vertex: Vertex, instance: InstanceInput) -> VertexOutput {
     // Inversing a matrix is an expensive calculation so it should be done on the CPU and passed in as a buffer
     // similar to the model matrix.   
     out.normal = normal_mat * vertex.normal;
-    out.uv = vertex.uv;
+    out.uv = vertex.uv * instance.uv_transform.zw + instance.uv_transform.xy;
     out.frag_pos = vec4<f32>(model_mat * vec4<f32>(vertex.position, 1.0)).xyz;
-    out.clip_position = projection_mat * view_mat * vec4<f32>(out.frag_pos, 1.0);
+    out.clip_position = view.view_proj * vec4<f32>(out.frag_pos, 1.0);
     out.shininess = instance.shininess;
+    out.highlight = instance.highlight;
+    out.emission = instance.emission;
+    out.diffuse_tint = instance.diffuse_tint;
+    out.specular_tint = instance.specular_tint;
     return out;
 }
 
@@ -147,50 +248,133 @@ struct FragmentInput {
     @location(2) uv: vec2<f32>,
     @location(3) frag_pos: vec3<f32>,
     @location(4) shininess: f32,
+    @location(5) highlight: f32,
+    @location(6) emission: f32,
+    @location(7) diffuse_tint: vec4<f32>,
+    @location(8) specular_tint: vec4<f32>,
 };
 
-fn calc_dir_light(light: DirLight, normal: vec3<f32>, view_dir: vec3<f32>, shininess: f32, uv: vec2<f32>) -> vec4<f32> {
+// What the lights get reflected off, with the maps already sampled and tinted
+struct Surface {
+    diffuse: vec4<f32>,
+    specular: vec4<f32>,
+    shininess: f32,
+};
+
+// Returns how much of the fragment is lit by the directional light, 0.0 being fully in shadow
+fn calc_dir_shadow(normal: vec3<f32>, frag_pos: vec3<f32>, light_dir: vec3<f32>) -> f32 {
+    let light_space_pos = dir_shadow.light_space * vec4<f32>(frag_pos, 1.0);
+    let ndc = light_space_pos.xyz / light_space_pos.w;
+    // Everything outside of the shadow map is lit
+    if (any(ndc.xy < vec2<f32>(-1.0)) || any(ndc.xy > vec2<f32>(1.0)) || ndc.z > 1.0) {
+        return 1.0;
+    }
+    // NDC y points up while texture coords y points down
+    let shadow_uv = ndc.xy * vec2<f32>(0.5, -0.5) + vec2<f32>(0.5);
+    // Surfaces at a steep angle to the light need a bigger bias to avoid shadow acne
+    let bias = max(dir_shadow.depth_bias * 10.0 * (1.0 - dot(normal, light_dir)), dir_shadow.depth_bias);
+    let depth = ndc.z - bias;
+
+    // Percentage closer filtering, average the comparisons of the neighbouring texels
+    let texel_size = 1.0 / vec2<f32>(textureDimensions(dir_shadow_map));
+    let half_kernel = i32(dir_shadow.filter_size / 2u);
+    var lit = 0.0;
+    for (var x = -half_kernel; x <= half_kernel; x++) {
+        for (var y = -half_kernel; y <= half_kernel; y++) {
+            let offset = vec2<f32>(f32(x), f32(y)) * texel_size;
+            lit += textureSampleCompareLevel(dir_shadow_map, shadow_sampler, shadow_uv + offset, depth);
+        }
+    }
+    let kernel_width = f32(half_kernel * 2 + 1);
+    return lit / (kernel_width * kernel_width);
+}
+
+fn calc_dir_light(light: DirLight, normal: vec3<f32>, view_dir: vec3<f32>, surface: Surface, shadow: f32) -> vec4<f32> {
     let light_dir = normalize(-light.direction);
     // Diffuse
     let diff = max(dot(normal, light_dir), 0.0);
     // Specular
     let reflect_dir = reflect(-light_dir, normal);
-    let spec = pow(max(dot(view_dir, reflect_dir), 0.0), shininess);
+    let spec = pow(max(dot(view_dir, reflect_dir), 0.0), surface.shininess);
     // Combined
-    let ambient = light.ambient * textureSample(diff_tex, diff_tex_sampler, uv);
-    let diffuse = light.diffuse * diff * textureSample(diff_tex, diff_tex_sampler, uv);
+    let ambient = light.ambient * surface.diffuse;
+    let diffuse = light.diffuse * diff * surface.diffuse;
 
-    let specular = light.specular * spec * textureSample(spec_tex, spec_tex_sampler, uv);
-    return ambient + diffuse + specular;
+    let specular = light.specular * spec * surface.specular;
+    // Shadows only block the direct light, the ambient term stays
+    return ambient + (diffuse + specular) * shadow;
+}
+
+// Returns how much of the fragment is lit by the point light, 0.0 being fully in shadow
+fn calc_point_shadow(light: PointLight, frag_pos: vec3<f32>) -> f32 {
+    // The shadow maps hold the distance to the light divided by the far plane
+    let frag_to_light = light.position - frag_pos;
+    let depth = length(frag_to_light) / point_shadow.far - point_shadow.depth_bias;
+    if (depth > 1.0) {
+        return 1.0;
+    }
+#ifdef NO_CUBE_ARRAY_TEXTURES_SUPPORT
+    // Only the first shadow casting light gets a cube map
+    return textureSampleCompareLevel(point_shadow_maps, shadow_sampler, frag_to_light, depth);
+#else
+    return textureSampleCompareLevel(point_shadow_maps, shadow_sampler, frag_to_light, light.shadow_index, depth);
+#endif
 }
 
-fn calc_point_light(light: PointLight, normal: vec3<f32>, frag_pos: vec3<f32>, view_dir: vec3<f32>, shininess: f32, uv: vec2<f32>) -> vec4<f32> {
+fn calc_point_light(light: PointLight, normal: vec3<f32>, frag_pos: vec3<f32>, view_dir: vec3<f32>, surface: Surface) -> vec4<f32> {
     let light_dir = normalize(light.position - frag_pos);
     // Diffuse
     let diff = max(dot(normal, light_dir), 0.0);
     // Specular
     let reflect_dir = reflect(-light_dir, normal);
-    let spec = pow(max(dot(view_dir, reflect_dir), 0.0), shininess);
+    let spec = pow(max(dot(view_dir, reflect_dir), 0.0), surface.shininess);
     // Attenuation
     let dist = length(light.position - frag_pos);
     let attenuation = 1.0 / (light.constant + light.lin * dist + light.quadratic * (dist * dist));
     // Combined
-    var ambient = light.ambient * textureSample(diff_tex, diff_tex_sampler, uv);
-    var diffuse = light.diffuse * diff * textureSample(diff_tex, diff_tex_sampler, uv);
-    var specular = light.specular * spec * textureSample(spec_tex, spec_tex_sampler, uv);
+    var ambient = light.ambient * surface.diffuse;
+    var diffuse = light.diffuse * diff * surface.diffuse;
+    var specular = light.specular * spec * surface.specular;
     ambient *= attenuation;
     diffuse *= attenuation;
     specular *= attenuation;
+    if (light.shadow_index >= 0) {
+        let shadow = calc_point_shadow(light, frag_pos);
+        diffuse *= shadow;
+        specular *= shadow;
+    }
     return ambient + diffuse + specular;
 }
 
-fn calc_spot_light(light: Spotlight, normal: vec3<f32>, frag_pos: vec3<f32>, view_dir: vec3<f32>, shininess: f32, uv: vec2<f32>) -> vec4<f32> {
+// Returns how much of the fragment is lit by the spotlight, 0.0 being fully in shadow
+fn calc_spot_shadow(light: Spotlight, frag_pos: vec3<f32>) -> f32 {
+    let light_space_pos = spotlight_shadow.light_spaces[light.shadow_index] * vec4<f32>(frag_pos, 1.0);
+    let ndc = light_space_pos.xyz / light_space_pos.w;
+    // Everything outside of the light frustum is lit, the cone takes care of the rest
+    if (light_space_pos.w <= 0.0 || any(abs(ndc.xy) > vec2<f32>(1.0)) || ndc.z > 1.0) {
+        return 1.0;
+    }
+    // NDC y points up while texture coords y points down
+    let shadow_uv = ndc.xy * vec2<f32>(0.5, -0.5) + vec2<f32>(0.5);
+    let depth = ndc.z - spotlight_shadow.depth_bias;
+
+    // Soften the edges by averaging comparisons spread over a disk around the fragment
+    let texel_size = 1.0 / vec2<f32>(textureDimensions(spotlight_shadow_maps));
+    var lit = 0.0;
+    for (var i = 0; i < 16; i++) {
+        let offset = poisson_disk[i] * spotlight_shadow.softness * texel_size;
+        lit += textureSampleCompareLevel(spotlight_shadow_maps, shadow_sampler, shadow_uv + offset, light.shadow_index, depth);
+    }
+    return lit / 16.0;
+}
+
+fn calc_spot_light(light: Spotlight, normal: vec3<f32>, frag_pos: vec3<f32>, view_dir: vec3<f32>, surface: Surface) -> vec4<f32> {
     let light_dir = normalize(light.position - frag_pos);
     // Diffuse
     let diff = max(dot(normal, light_dir), 0.0);
     // Specular
     let reflect_dir = reflect(-light_dir, normal);
-    let spec = pow(max(dot(view_dir, reflect_dir), 0.0), shininess);
+    let spec = pow(max(dot(view_dir, reflect_dir), 0.0), surface.shininess);
     // Attenuation
     let dist = length(light.position - frag_pos);
     let attenuation = 1.0 / (light.constant + light.lin * dist + light.quadratic * (dist * dist));
@@ -199,13 +383,18 @@ fn calc_spot_light(light: Spotlight, normal: vec3<f32>, frag_pos: vec3<f32>, vie
     let epsilon = light.cutoff - light.outer_cutoff;
     let intensity = clamp((theta - light.outer_cutoff) / epsilon, 0.0, 1.0);
     // Combined
-    var ambient = light.ambient * textureSample(diff_tex, diff_tex_sampler, uv);
-    var diffuse = light.diffuse * diff * textureSample(diff_tex, diff_tex_sampler, uv);
-    var specular = light.specular * spec * textureSample(spec_tex, spec_tex_sampler, uv);
+    var ambient = light.ambient * surface.diffuse;
+    var diffuse = light.diffuse * diff * surface.diffuse;
+    var specular = light.specular * spec * surface.specular;
 
     ambient *= attenuation * intensity;
     diffuse *= attenuation * intensity;
     specular *= attenuation * intensity;
+    if (light.shadow_index >= 0) {
+        let shadow = calc_spot_shadow(light, frag_pos);
+        diffuse *= shadow;
+        specular *= shadow;
+    }
 
     return ambient + diffuse + specular;
 }
@@ -225,18 +414,42 @@ fn fragment(
     // But we could have just transformed the vertex out values from the world coord to a view coord
     // That way we would get the view pos for free (i.e. multiply the "frag_pos" and "normal" by both the "model" and "view mat", I think)
     // The light direction is pointing from the frag pos to the light source so negate that
-    let view_dir = normalize(view_pos - in.frag_pos);
+    let view_dir = normalize(view.world_position - in.frag_pos);
+    let specular_map = textureSample(spec_tex, spec_tex_sampler, in.uv);
+    let surface = Surface(
+        textureSample(diff_tex, diff_tex_sampler, in.uv) * in.diffuse_tint,
+        specular_map * in.specular_tint,
+        in.shininess,
+    );
 
     // Phase 1: Directional lighting
-    var result = calc_dir_light(dir_light, norm, view_dir, in.shininess, in.uv);
+    var result = vec4<f32>(0.0);
+    for (var i = 0u; i < light_counts.directional_count; i++) {
+        var shadow = 1.0;
+        if (dir_shadow.enabled != 0u && i == dir_shadow.light_index) {
+            shadow = calc_dir_shadow(norm, in.frag_pos, normalize(-dir_lights[i].direction));
+        }
+        result += calc_dir_light(dir_lights[i], norm, view_dir, surface, shadow);
+    }
     // Phase 2: Point lights
-    for (var i = 0; i < 4; i++) {
-        result += calc_point_light(point_l[i], norm, in.frag_pos, view_dir, in.shininess, in.uv);
+    for (var i = 0u; i < light_counts.point_count; i++) {
+        result += calc_point_light(point_l[i], norm, in.frag_pos, view_dir, surface);
+    }
+    // Phase 3: Spot lights
+    for (var i = 0u; i < light_counts.spot_count; i++) {
+        result += calc_spot_light(spotlights[i], norm, in.frag_pos, view_dir, surface);
+    }
+
+    // Phase 4: Emission, which doesn't depend on any light
+    var emitted = textureSample(emission_tex, emission_tex_sampler, in.uv + emission.uv_offset).xyz;
+    emitted *= emission.strength * in.emission;
+    if (emission.mask_by_specular != 0u && specular_map.r > 0.0) {
+        emitted = vec3<f32>(0.0);
     }
-    // Phase 3: Spot light
-    result += calc_spot_light(spotlight, norm, in.frag_pos, view_dir, in.shininess, in.uv);
+    result += vec4<f32>(emitted, 0.0);
 
-    //let emission = textureSample(emission_tex, emission_tex_sampler, in.uv).xyz * 3.0;
+    // Brighten the instance under the cursor
+    result += vec4<f32>(vec3<f32>(0.25), 0.0) * in.highlight;
 
     return vec4<f32>(result.xyz, 1.0);
 }
@@ -0,0 +1,60 @@
+struct InstanceCulling {
+    planes: array<vec4<f32>, 6>,
+    aabb_center: vec3<f32>,
+    instance_count: u32,
+    aabb_half_extents: vec3<f32>,
+    // Size of an instance in 32 bit words, the instances are read as plain words so the shader
+    // doesn't need to know their layout past the model matrix
+    instance_words: u32,
+    plane_count: u32,
+};
+
+@group(0) @binding(0)
+var<uniform> culling: InstanceCulling;
+@group(0) @binding(1)
+var<storage, read> instances: array<f32>;
+@group(0) @binding(2)
+var<storage, read_write> visible_instances: array<f32>;
+// The arguments of the indirect draw, the instance count is the second word whether it's indexed
+// or not
+@group(0) @binding(3)
+var<storage, read_write> draw_args: array<atomic<u32>>;
+
+@compute @workgroup_size(64)
+fn cull(@builtin(global_invocation_id) id: vec3<u32>) {
+    let instance = id.x;
+    if (instance >= culling.instance_count) {
+        return;
+    }
+
+    // The model matrix comes first, column by column
+    let base = instance * culling.instance_words;
+    let x_axis = vec3<f32>(instances[base], instances[base + 1u], instances[base + 2u]);
+    let y_axis = vec3<f32>(instances[base + 4u], instances[base + 5u], instances[base + 6u]);
+    let z_axis = vec3<f32>(instances[base + 8u], instances[base + 9u], instances[base + 10u]);
+    let translation = vec3<f32>(instances[base + 12u], instances[base + 13u], instances[base + 14u]);
+    let center = translation
+        + x_axis * culling.aabb_center.x
+        + y_axis * culling.aabb_center.y
+        + z_axis * culling.aabb_center.z;
+
+    // Same oriented bounding box test as bevy's Frustum::intersects_obb
+    for (var i = 0u; i < culling.plane_count; i = i + 1u) {
+        let plane = culling.planes[i];
+        let axes = abs(vec3<f32>(
+            dot(plane.xyz, x_axis),
+            dot(plane.xyz, y_axis),
+            dot(plane.xyz, z_axis),
+        ));
+        let relative_radius = dot(culling.aabb_half_extents, axes);
+        if (dot(plane, vec4<f32>(center, 1.0)) + relative_radius <= 0.0) {
+            return;
+        }
+    }
+
+    let slot = atomicAdd(&draw_args[1], 1u);
+    let visible_base = slot * culling.instance_words;
+    for (var word = 0u; word < culling.instance_words; word = word + 1u) {
+        visible_instances[visible_base + word] = instances[base + word];
+    }
+}
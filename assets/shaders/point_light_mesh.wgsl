@@ -2,12 +2,6 @@
 #import bevy_pbr::mesh_bindings
 #import bevy_pbr::mesh_view_bindings
 
-@group(2) @binding(0)
-var<uniform> view_mat: mat4x4<f32>;
-
-@group(2) @binding(1)
-var<uniform> projection_mat: mat4x4<f32>;
-
 struct InstanceInput {
     @location(3) model_mat_0: vec4<f32>,
     @location(4) model_mat_1: vec4<f32>,
@@ -45,7 +39,7 @@ fn vertex(vertex: Vertex, instance: InstanceInput) -> VertexOutput {
     // This needs to be done whenever we pass in viewport size coords (1280 x 720)
     //out.clip_position = mesh_position_local_to_clip(mesh.model, vec4<f32>(vertex.position, 1.0));
     // Otherwise, if we have normalized coords (-1, 1) we can just copy the position
-    out.clip_position = projection_mat * view_mat * model_mat * vec4<f32>(vertex.position, 1.0);
+    out.clip_position = view.view_proj * model_mat * vec4<f32>(vertex.position, 1.0);
     out.position = vec4<f32>(vertex.position, 1.0);
     out.ambient = instance.ambient;
     out.diffuse = instance.diffuse;
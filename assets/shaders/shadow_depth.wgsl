@@ -0,0 +1,45 @@
+// Depth only pass rendering the shadow casters from a light's point of view
+struct ShadowPass {
+    light_space: mat4x4<f32>,
+    // Only used when rendering linear depth for point lights
+    light_position: vec3<f32>,
+    far: f32,
+};
+
+@group(0) @binding(0)
+var<uniform> shadow_pass: ShadowPass;
+
+struct Vertex {
+    @location(0) position: vec3<f32>,
+};
+
+struct InstanceInput {
+    @location(3) model_mat_0: vec4<f32>,
+    @location(4) model_mat_1: vec4<f32>,
+    @location(5) model_mat_2: vec4<f32>,
+    @location(6) model_mat_3: vec4<f32>,
+}
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) world_position: vec3<f32>,
+};
+
+@vertex
+fn vertex(vertex: Vertex, instance: InstanceInput) -> VertexOutput {
+    var out: VertexOutput;
+    let model_mat = mat4x4<f32>(instance.model_mat_0, instance.model_mat_1, instance.model_mat_2, instance.model_mat_3);
+    let world_position = model_mat * vec4<f32>(vertex.position, 1.0);
+    out.clip_position = shadow_pass.light_space * world_position;
+    out.world_position = world_position.xyz;
+    return out;
+}
+
+#ifdef LINEAR_DEPTH
+// Point light shadows store the distance to the light, so that the cube map can be sampled with
+// the direction to the light alone regardless of which face it lands on
+@fragment
+fn fragment(in: VertexOutput) -> @builtin(frag_depth) f32 {
+    return length(in.world_position - shadow_pass.light_position) / shadow_pass.far;
+}
+#endif
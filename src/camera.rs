@@ -1,79 +1,448 @@
-use crate::AppState;
+use crate::{
+    AppState, CameraAction, CameraControllerSettings, CameraPathPlayer, Spotlight,
+    CAMERA_SETTINGS_PATH,
+};
 use bevy::{
+    core_pipeline::core_3d,
+    ecs::query::ChangeTrackers,
     input::mouse::{MouseMotion, MouseWheel},
     prelude::*,
+    render::{
+        camera::{
+            CameraProjection, CameraProjectionPlugin, CameraRenderGraph, CameraUpdateSystem,
+            DepthCalculation, RenderTarget, Viewport,
+        },
+        primitives::Frustum,
+        view::{update_frusta, VisibilitySystems, VisibleEntities},
+    },
+    transform::TransformSystem,
+    window::WindowResized,
 };
 
 pub struct CameraPlugin;
 
 impl Plugin for CameraPlugin {
     fn build(&self, app: &mut App) {
+        app.insert_resource(CameraControllerSettings::load_or_default(
+            CAMERA_SETTINGS_PATH,
+        ))
+        .add_plugin(CameraProjectionPlugin::<CustomCamera>::default())
+        .add_system_to_stage(
+            CoreStage::PostUpdate,
+            Self::camera_transform_system.before(TransformSystem::TransformPropagate),
+        )
+        .add_system_to_stage(
+            CoreStage::PostUpdate,
+            Self::aspect_ratio_system.before(CameraUpdateSystem),
+        )
+        .add_system_to_stage(
+            CoreStage::PostUpdate,
+            update_frusta::<CustomCamera>
+                .after(TransformSystem::TransformPropagate)
+                .before(VisibilitySystems::CheckVisibility),
+        );
         app.add_system_set(
-            SystemSet::on_update(AppState::Main).with_system(Self::camera_move_system),
+            SystemSet::on_update(AppState::Main).with_system(Self::camera_mode_system),
         )
+        .add_system_set(SystemSet::on_update(AppState::Main).with_system(Self::camera_move_system))
         .add_system_set(SystemSet::on_update(AppState::Main).with_system(Self::camera_look_system))
-        .add_system_set(SystemSet::on_update(AppState::Main).with_system(Self::camera_zoom_system));
+        .add_system_set(SystemSet::on_update(AppState::Main).with_system(Self::camera_zoom_system))
+        .add_system_set(
+            SystemSet::on_update(AppState::Main).with_system(
+                Self::flashlight_system
+                    .after(Self::camera_move_system)
+                    .after(Self::camera_look_system),
+            ),
+        );
+    }
+}
+
+/// Attaches a [`Spotlight`] to the [`CustomCamera`] so it shines wherever the camera looks
+#[derive(Component)]
+pub struct Flashlight;
+
+/// How the mouse and keyboard drive a [`CustomCamera`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CameraMode {
+    /// WASD to move, mouse to look around
+    #[default]
+    FreeFly,
+    /// Mouse rotates around the focus point, holding [`CameraAction::Pan`] (alt by default) pans
+    /// it instead and the wheel dollies toward it
+    Orbit,
+}
+
+/// Controller state of a [`CustomCamera`], [`CameraAction::SwitchMode`] switches between modes.
+///
+/// The focus point is always `orbit_distance` in front of the camera, so switching modes keeps
+/// the camera where it is.
+#[derive(Component, Debug, Clone)]
+pub struct CameraController {
+    pub mode: CameraMode,
+    pub orbit_distance: f32,
+    /// Mouse motion that smoothing hasn't applied yet
+    pending_mouse_delta: Vec2,
+}
+
+impl Default for CameraController {
+    fn default() -> Self {
+        Self {
+            mode: CameraMode::FreeFly,
+            orbit_distance: 3.0,
+            pending_mouse_delta: Vec2::ZERO,
+        }
+    }
+}
+
+impl CameraController {
+    /// Below this many pixels the rest of the pending motion gets applied at once
+    const MIN_PENDING_MOUSE_DELTA: f32 = 0.01;
+
+    /// Returns the part of the mouse motion to apply this frame, where the motion left to apply
+    /// decays exponentially with a time constant of `smoothing` seconds. The sum of what gets
+    /// applied over time doesn't depend on the frame times.
+    fn smooth_mouse_delta(
+        &mut self,
+        mouse_delta: Vec2,
+        delta_seconds: f32,
+        smoothing: f32,
+    ) -> Vec2 {
+        self.pending_mouse_delta += mouse_delta;
+
+        let mut applied = self.pending_mouse_delta * (1.0 - (-delta_seconds / smoothing).exp());
+        if (self.pending_mouse_delta - applied).length() < Self::MIN_PENDING_MOUSE_DELTA {
+            applied = self.pending_mouse_delta;
+        }
+        self.pending_mouse_delta -= applied;
+        applied
+    }
+}
+
+/// Keeps the image of a [`CustomCamera`] at a fixed aspect ratio, whatever the size of the window.
+///
+/// The viewport gets shrunk to the largest centered rectangle of this aspect ratio, leaving black
+/// bars above and below (letterbox) or on the sides (pillarbox).
+#[derive(Component, Debug, Clone, Copy)]
+pub struct FixedAspectRatio(pub f32);
+
+type AspectRatioQuery<'a> = (
+    Entity,
+    &'a mut Camera,
+    &'a mut CustomCamera,
+    Option<&'a FixedAspectRatio>,
+    Option<ChangeTrackers<FixedAspectRatio>>,
+);
+
+/// A 3d camera whose transform and projection come from a [`CustomCamera`]
+#[derive(Bundle)]
+pub struct CustomCameraBundle {
+    pub custom_camera: CustomCamera,
+    pub controller: CameraController,
+    pub camera: Camera,
+    pub camera_render_graph: CameraRenderGraph,
+    pub camera_3d: Camera3d,
+    pub visible_entities: VisibleEntities,
+    pub frustum: Frustum,
+    pub transform: Transform,
+    pub global_transform: GlobalTransform,
+}
+
+impl Default for CustomCameraBundle {
+    fn default() -> Self {
+        Self {
+            custom_camera: default(),
+            controller: default(),
+            camera: default(),
+            camera_render_graph: CameraRenderGraph::new(core_3d::graph::NAME),
+            camera_3d: default(),
+            visible_entities: default(),
+            frustum: default(),
+            transform: default(),
+            global_transform: default(),
+        }
     }
 }
 
 impl CameraPlugin {
-    pub fn camera_move_system(
-        mut camera: ResMut<CustomCamera>,
+    fn camera_mode_system(
+        mut controllers: Query<(&mut CameraController, &mut CustomCamera)>,
         input: Res<Input<KeyCode>>,
-        time: Res<Time>,
+        settings: Res<CameraControllerSettings>,
     ) {
-        let camera_speed: f32 = 2.5;
-        let mut translation = Vec3::ZERO;
-        let camera_right = camera.right();
-
-        if input.pressed(KeyCode::W) {
-            translation += camera.get_direction() * camera_speed;
+        if settings.just_pressed(&input, CameraAction::SwitchOrientation) {
+            for (_, mut camera) in &mut controllers {
+                let orientation = match camera.orientation {
+                    CameraOrientation::Euler => CameraOrientation::Quaternion,
+                    CameraOrientation::Quaternion => CameraOrientation::Euler,
+                };
+                camera.set_orientation(orientation);
+            }
         }
-        if input.pressed(KeyCode::S) {
-            translation -= camera.get_direction() * camera_speed;
+
+        if settings.just_pressed(&input, CameraAction::SwitchProjection) {
+            for (controller, mut camera) in &mut controllers {
+                let projection = match camera.projection {
+                    ProjectionMode::Perspective => ProjectionMode::Orthographic,
+                    ProjectionMode::Orthographic => ProjectionMode::Perspective,
+                };
+                camera.set_projection(projection, controller.orbit_distance);
+            }
         }
-        if input.pressed(KeyCode::A) {
-            translation -= camera_right * camera_speed;
+
+        if settings.just_pressed(&input, CameraAction::SwitchMode) {
+            for (mut controller, _) in &mut controllers {
+                controller.mode = match controller.mode {
+                    CameraMode::FreeFly => CameraMode::Orbit,
+                    CameraMode::Orbit => CameraMode::FreeFly,
+                };
+            }
         }
-        if input.pressed(KeyCode::D) {
-            translation += camera_right * camera_speed;
+    }
+
+    pub fn camera_move_system(
+        mut cameras: Query<(&mut CustomCamera, &CameraController), Without<CameraPathPlayer>>,
+        input: Res<Input<KeyCode>>,
+        settings: Res<CameraControllerSettings>,
+        time: Res<Time>,
+    ) {
+        let mut camera_speed = settings.move_speed;
+        if settings.pressed(&input, CameraAction::Sprint) {
+            camera_speed *= settings.sprint_multiplier;
         }
 
-        if translation != Vec3::ZERO {
-            camera.translate(translation * time.delta_seconds());
+        for (mut camera, controller) in &mut cameras {
+            if controller.mode != CameraMode::FreeFly {
+                continue;
+            }
+
+            let mut translation = Vec3::ZERO;
+            let camera_right = camera.right();
+
+            if settings.pressed(&input, CameraAction::Forward) {
+                translation += camera.get_direction() * camera_speed;
+            }
+            if settings.pressed(&input, CameraAction::Backward) {
+                translation -= camera.get_direction() * camera_speed;
+            }
+            if settings.pressed(&input, CameraAction::Left) {
+                translation -= camera_right * camera_speed;
+            }
+            if settings.pressed(&input, CameraAction::Right) {
+                translation += camera_right * camera_speed;
+            }
+            if settings.pressed(&input, CameraAction::Up) {
+                translation += camera.get_up() * camera_speed;
+            }
+            if settings.pressed(&input, CameraAction::Down) {
+                translation -= camera.get_up() * camera_speed;
+            }
+
+            if translation != Vec3::ZERO {
+                camera.translate(translation * time.delta_seconds());
+            }
+
+            let mut roll = 0.0;
+            if settings.pressed(&input, CameraAction::RollLeft) {
+                roll -= settings.roll_speed;
+            }
+            if settings.pressed(&input, CameraAction::RollRight) {
+                roll += settings.roll_speed;
+            }
+            if roll != 0.0 && camera.orientation == CameraOrientation::Quaternion {
+                camera.roll(roll * time.delta_seconds());
+            }
         }
     }
 
+    /// Turns mouse motion into rotation, or panning in orbit mode.
+    ///
+    /// Mouse motion is a distance, so it's applied as is rather than scaled by the frame time.
     pub fn camera_look_system(
-        mut camera: ResMut<CustomCamera>,
+        mut cameras: Query<(&mut CustomCamera, &mut CameraController), Without<CameraPathPlayer>>,
         mut mouse_motion: EventReader<MouseMotion>,
+        input: Res<Input<KeyCode>>,
+        settings: Res<CameraControllerSettings>,
         time: Res<Time>,
     ) {
-        let look_sensitivity: f32 = 0.1;
-        let mut rotation_offset = Vec2::ZERO;
+        let mut mouse_delta = Vec2::ZERO;
 
         for event in mouse_motion.iter() {
-            rotation_offset += event.delta * look_sensitivity * time.delta_seconds();
+            mouse_delta += event.delta;
         }
 
-        if rotation_offset != Vec2::ZERO {
-            camera.rotate(rotation_offset.x, -rotation_offset.y);
+        if settings.invert_y {
+            mouse_delta.y = -mouse_delta.y;
+        }
+
+        let panning = settings.pressed(&input, CameraAction::Pan);
+        for (mut camera, mut controller) in &mut cameras {
+            let mouse_delta = if settings.look_smoothing > 0.0 {
+                if mouse_delta == Vec2::ZERO && controller.pending_mouse_delta == Vec2::ZERO {
+                    continue;
+                }
+                controller.smooth_mouse_delta(
+                    mouse_delta,
+                    time.delta_seconds(),
+                    settings.look_smoothing,
+                )
+            } else {
+                mouse_delta
+            };
+
+            if mouse_delta == Vec2::ZERO {
+                continue;
+            }
+
+            let rotation_offset = mouse_delta * settings.look_sensitivity;
+            match controller.mode {
+                CameraMode::FreeFly => camera.rotate(rotation_offset.x, -rotation_offset.y),
+                CameraMode::Orbit if panning => {
+                    // Move the camera and its focus point together, faster the further away it is
+                    let camera_right = camera.right();
+                    let camera_up = camera_right.cross(camera.get_direction());
+                    let pan = (camera_up * mouse_delta.y - camera_right * mouse_delta.x)
+                        * settings.pan_sensitivity
+                        * controller.orbit_distance;
+                    camera.translate(pan);
+                }
+                CameraMode::Orbit => {
+                    let focus =
+                        camera.position + camera.get_direction() * controller.orbit_distance;
+                    camera.rotate(rotation_offset.x, -rotation_offset.y);
+                    camera.position = focus - camera.get_direction() * controller.orbit_distance;
+                }
+            }
+        }
+    }
+
+    /// Moves the flashlights along with the first [`CustomCamera`]
+    pub fn flashlight_system(
+        cameras: Query<&CustomCamera>,
+        mut query: Query<&mut Transform, (With<Flashlight>, With<Spotlight>)>,
+    ) {
+        let camera = match cameras.iter().next() {
+            Some(camera) => camera,
+            None => return,
+        };
+        for mut transform in &mut query {
+            *transform = camera.transform();
         }
     }
 
     fn camera_zoom_system(
-        mut camera: ResMut<CustomCamera>,
+        mut cameras: Query<(&mut CustomCamera, &mut CameraController), Without<CameraPathPlayer>>,
         mut mouse_wheel: EventReader<MouseWheel>,
+        settings: Res<CameraControllerSettings>,
     ) {
-        let sensitivity: f32 = 1.0;
-
         for event in mouse_wheel.iter() {
-            camera.zoom(event.y * sensitivity);
+            for (mut camera, mut controller) in &mut cameras {
+                if camera.projection == ProjectionMode::Orthographic {
+                    // Moving an orthographic camera doesn't change the size of anything
+                    camera.zoom_orthographic(event.y * settings.ortho_zoom_sensitivity);
+                    continue;
+                }
+
+                match controller.mode {
+                    CameraMode::FreeFly => camera.zoom(event.y * settings.zoom_sensitivity),
+                    CameraMode::Orbit => {
+                        // Dolly toward the focus point, keeping it in place
+                        let focus =
+                            camera.position + camera.get_direction() * controller.orbit_distance;
+                        controller.orbit_distance = (controller.orbit_distance
+                            * (1.0 - event.y * settings.dolly_sensitivity))
+                            .max(camera.near);
+                        camera.position =
+                            focus - camera.get_direction() * controller.orbit_distance;
+                    }
+                }
+            }
         }
     }
+
+    /// Fits the viewport of cameras with a [`FixedAspectRatio`] to their window. Bevy's
+    /// `camera_system` then fits the projection to the viewport, like it does on every resize.
+    fn aspect_ratio_system(
+        mut resized_events: EventReader<WindowResized>,
+        removed_fixed_aspects: RemovedComponents<FixedAspectRatio>,
+        windows: Res<Windows>,
+        mut cameras: Query<AspectRatioQuery>,
+    ) {
+        let resized_windows = resized_events
+            .iter()
+            .map(|event| event.id)
+            .collect::<Vec<_>>();
+
+        for (entity, mut camera, mut custom_camera, fixed_aspect, fixed_aspect_tracker) in
+            &mut cameras
+        {
+            let window = match &camera.target {
+                RenderTarget::Window(id) => match windows.get(*id) {
+                    Some(window) => window,
+                    None => continue,
+                },
+                // Image targets never get resized
+                RenderTarget::Image(_) => continue,
+            };
+            let removed = removed_fixed_aspects
+                .iter()
+                .any(|removed| removed == entity);
+            let needs_update = removed
+                || fixed_aspect_tracker.map_or(false, |tracker| tracker.is_changed())
+                || (fixed_aspect.is_some() && resized_windows.contains(&window.id()));
+            if !needs_update {
+                continue;
+            }
+
+            let window_size = UVec2::new(window.physical_width(), window.physical_height());
+            if window_size.x == 0 || window_size.y == 0 {
+                // Minimized
+                continue;
+            }
+
+            camera.viewport = fixed_aspect.map(|FixedAspectRatio(aspect_ratio)| {
+                fixed_aspect_viewport(window_size, *aspect_ratio)
+            });
+            // The camera system only refits the projection when the window or the projection
+            // changed, not the viewport
+            custom_camera.set_changed();
+        }
+    }
+
+    /// Places the camera entity where the [`CustomCamera`] is
+    fn camera_transform_system(
+        mut cameras: Query<(&CustomCamera, &mut Transform), Changed<CustomCamera>>,
+    ) {
+        for (camera, mut transform) in &mut cameras {
+            *transform = camera.transform();
+        }
+    }
+}
+
+/// How a [`CustomCamera`] stores its orientation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CameraOrientation {
+    /// `yaw` and `pitch` around the fixed `up` vector, pitch is clamped so the view never flips
+    #[default]
+    Euler,
+    /// Free `rotation` that can roll, for flight-style cameras
+    Quaternion,
 }
 
-#[derive(Debug, Clone, Default)]
+/// Which projection [`CustomCamera::get_proj`] builds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ProjectionMode {
+    #[default]
+    Perspective,
+    /// Parallel projection showing `ortho_scale` units above and below the center of the view
+    Orthographic,
+}
+
+/// Position, orientation and projection of a camera entity.
+///
+/// This is the camera's [`CameraProjection`], and its [`Transform`] is kept in sync with it, so
+/// every view gets rendered with the matrices of its own `CustomCamera`.
+#[derive(Component, Reflect, Debug, Clone, Default)]
+#[reflect(Component, Default)]
 pub struct CustomCamera {
     pub position: Vec3,
     // The positive z axis is going through the screen toward you
@@ -81,38 +450,164 @@ pub struct CustomCamera {
     pub yaw: f32,
     pub pitch: f32,
     pub up: Vec3,
+    /// Only used with [`CameraOrientation::Quaternion`]
+    pub rotation: Quat,
+    #[reflect(ignore)]
+    pub orientation: CameraOrientation,
+    #[reflect(ignore)]
+    pub projection: ProjectionMode,
     pub fov: f32,
+    /// Half the height of the view with [`ProjectionMode::Orthographic`], in world units
+    pub ortho_scale: f32,
     pub aspect_ratio: f32,
     pub near: f32,
+    /// Objects further than this get culled, even with `infinite_far`
     pub far: f32,
+    /// Perspective projection without a far plane, which keeps more depth precision up close
+    pub infinite_far: bool,
 }
 
 impl CustomCamera {
+    /// Keeps the direction from reaching `up`, where the view would flip
+    const MAX_PITCH: f32 = 89.0 * std::f32::consts::PI / 180.0;
+
+    /// Where the camera is and where it looks, its view matrix is the inverse of this
+    pub fn transform(&self) -> Transform {
+        match self.orientation {
+            CameraOrientation::Euler => Transform::from_translation(self.position)
+                .looking_at(self.position + self.get_direction(), self.up),
+            CameraOrientation::Quaternion => {
+                Transform::from_translation(self.position).with_rotation(self.rotation)
+            }
+        }
+    }
+
     pub fn get_view(&self) -> Mat4 {
-        Mat4::look_at_rh(self.position, self.position + self.get_direction(), self.up)
+        self.transform().compute_matrix().inverse()
     }
 
+    /// World space ray going through `ndc` on the screen, as an origin on the near plane and a
+    /// normalized direction
+    pub fn ray_from_ndc(&self, ndc: Vec2) -> (Vec3, Vec3) {
+        let ndc_to_world = (self.get_proj() * self.get_view()).inverse();
+        // Reverse-Z puts the near plane at 1.0, 0.0 would be at infinity with `infinite_far`
+        let near = ndc_to_world.project_point3(ndc.extend(1.0));
+        let further = ndc_to_world.project_point3(ndc.extend(0.5));
+        (near, (further - near).normalize())
+    }
+
+    /// Reverse-Z projection matrix, depth goes from 1.0 at the near plane to 0.0 at the far plane
+    /// like bevy's own cameras, which keeps a lot more precision in the distance
     pub fn get_proj(&self) -> Mat4 {
-        Mat4::perspective_rh(
-            self.fov.to_radians(),
-            self.aspect_ratio,
-            self.near,
-            self.far,
-        )
+        match self.projection {
+            ProjectionMode::Perspective if self.infinite_far => {
+                Mat4::perspective_infinite_reverse_rh(
+                    self.fov.to_radians(),
+                    self.aspect_ratio,
+                    self.near,
+                )
+            }
+            // Swapping the planes reverses the depth range
+            ProjectionMode::Perspective => Mat4::perspective_rh(
+                self.fov.to_radians(),
+                self.aspect_ratio,
+                self.far,
+                self.near,
+            ),
+            ProjectionMode::Orthographic => {
+                let half_width = self.ortho_scale * self.aspect_ratio;
+                Mat4::orthographic_rh(
+                    -half_width,
+                    half_width,
+                    -self.ortho_scale,
+                    self.ortho_scale,
+                    self.far,
+                    self.near,
+                )
+            }
+        }
+    }
+
+    /// Switches the projection, sizing the orthographic view so whatever is `focus_distance` in
+    /// front of the camera stays the same size on screen
+    pub fn set_projection(&mut self, projection: ProjectionMode, focus_distance: f32) {
+        if projection == ProjectionMode::Orthographic && self.projection != projection {
+            self.ortho_scale = focus_distance * (self.fov.to_radians() / 2.0).tan();
+        }
+        self.projection = projection;
     }
 
     pub fn get_direction(&self) -> Vec3 {
-        Vec3::new(
-            self.yaw.cos() * self.pitch.cos(),
-            self.pitch.sin(),
-            self.yaw.sin() * self.pitch.cos(),
-        )
-        .normalize()
+        match self.orientation {
+            CameraOrientation::Euler => Vec3::new(
+                self.yaw.cos() * self.pitch.cos(),
+                self.pitch.sin(),
+                self.yaw.sin() * self.pitch.cos(),
+            )
+            .normalize(),
+            CameraOrientation::Quaternion => self.rotation * Vec3::NEG_Z,
+        }
+    }
+
+    /// The camera's own up, which only differs from `up` once it pitches or rolls
+    pub fn get_up(&self) -> Vec3 {
+        match self.orientation {
+            CameraOrientation::Euler => self.up,
+            CameraOrientation::Quaternion => self.rotation * Vec3::Y,
+        }
     }
 
+    /// Turns right by `yaw` and up by `pitch` radians, around the camera's own axes for
+    /// [`CameraOrientation::Quaternion`]
     pub fn rotate(&mut self, yaw: f32, pitch: f32) {
-        self.yaw += yaw;
-        self.pitch += pitch;
+        match self.orientation {
+            CameraOrientation::Euler => {
+                self.yaw += yaw;
+                self.pitch = (self.pitch + pitch).clamp(-Self::MAX_PITCH, Self::MAX_PITCH);
+            }
+            CameraOrientation::Quaternion => {
+                self.rotation =
+                    (self.rotation * Quat::from_rotation_y(-yaw) * Quat::from_rotation_x(pitch))
+                        .normalize();
+            }
+        }
+    }
+
+    /// Rolls clockwise by `angle` radians, only [`CameraOrientation::Quaternion`] can roll
+    pub fn roll(&mut self, angle: f32) {
+        if self.orientation == CameraOrientation::Quaternion {
+            self.rotation = (self.rotation * Quat::from_rotation_z(-angle)).normalize();
+        }
+    }
+
+    /// Switches how the orientation is stored while keeping the camera looking the same way.
+    /// Going back to [`CameraOrientation::Euler`] levels out any roll.
+    pub fn set_orientation(&mut self, orientation: CameraOrientation) {
+        if self.orientation == orientation {
+            return;
+        }
+
+        match orientation {
+            CameraOrientation::Euler => (self.yaw, self.pitch) = self.yaw_pitch(),
+            CameraOrientation::Quaternion => {
+                self.rotation = self.transform().rotation;
+            }
+        }
+        self.orientation = orientation;
+    }
+
+    /// Yaw and pitch of the direction the camera looks in, any roll gets lost
+    pub fn yaw_pitch(&self) -> (f32, f32) {
+        match self.orientation {
+            CameraOrientation::Euler => (self.yaw, self.pitch),
+            CameraOrientation::Quaternion => {
+                let direction = self.get_direction();
+                (
+                    direction.z.atan2(direction.x),
+                    direction.y.asin().clamp(-Self::MAX_PITCH, Self::MAX_PITCH),
+                )
+            }
+        }
     }
 
     pub fn translate(&mut self, position: Vec3) {
@@ -122,11 +617,219 @@ impl CustomCamera {
     }
 
     pub fn right(&self) -> Vec3 {
-        self.get_direction().cross(self.up).normalize()
+        match self.orientation {
+            CameraOrientation::Euler => self.get_direction().cross(self.up).normalize(),
+            CameraOrientation::Quaternion => self.rotation * Vec3::X,
+        }
     }
 
     pub fn zoom(&mut self, amount: f32) {
         self.fov -= amount;
         self.fov = self.fov.clamp(1.0, 45.0);
     }
+
+    /// Shrinks the orthographic view by `factor` of its size, or grows it for negative factors
+    pub fn zoom_orthographic(&mut self, factor: f32) {
+        self.ortho_scale = (self.ortho_scale * (1.0 - factor)).clamp(0.01, self.far);
+    }
+}
+
+/// The largest viewport with the given aspect ratio that fits centered in the window
+fn fixed_aspect_viewport(window_size: UVec2, aspect_ratio: f32) -> Viewport {
+    let window_aspect_ratio = window_size.x as f32 / window_size.y as f32;
+    let physical_size = if window_aspect_ratio > aspect_ratio {
+        // Window is too wide, pillarbox
+        UVec2::new(
+            (window_size.y as f32 * aspect_ratio).round() as u32,
+            window_size.y,
+        )
+    } else {
+        // Window is too tall, letterbox
+        UVec2::new(
+            window_size.x,
+            (window_size.x as f32 / aspect_ratio).round() as u32,
+        )
+    }
+    .max(UVec2::ONE);
+
+    Viewport {
+        physical_position: (window_size - physical_size) / 2,
+        physical_size,
+        depth: 0.0..1.0,
+    }
+}
+
+impl CameraProjection for CustomCamera {
+    fn get_projection_matrix(&self) -> Mat4 {
+        self.get_proj()
+    }
+
+    fn update(&mut self, width: f32, height: f32) {
+        self.aspect_ratio = width / height;
+    }
+
+    fn depth_calculation(&self) -> DepthCalculation {
+        match self.projection {
+            ProjectionMode::Perspective => DepthCalculation::Distance,
+            ProjectionMode::Orthographic => DepthCalculation::ZDifference,
+        }
+    }
+
+    fn far(&self) -> f32 {
+        self.far
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::utils::{Duration, Instant};
+
+    /// Runs [`CameraPlugin::camera_look_system`] for `frames` frames of `frame_time` seconds, the
+    /// mouse moving by `motion(frame)` on each of them
+    fn run_look_system(
+        settings: CameraControllerSettings,
+        frame_time: f32,
+        frames: u32,
+        motion: impl Fn(u32) -> Vec2,
+    ) -> CustomCamera {
+        let mut world = World::new();
+        world.insert_resource(settings);
+        world.insert_resource(Input::<KeyCode>::default());
+        world.insert_resource(Events::<MouseMotion>::default());
+        let start = Instant::now();
+        let mut time = Time::default();
+        time.update_with_instant(start);
+        world.insert_resource(time);
+
+        let camera = world
+            .spawn()
+            .insert(CustomCamera {
+                yaw: START_YAW.to_radians(),
+                up: Vec3::Y,
+                ..default()
+            })
+            .insert(CameraController::default())
+            .id();
+
+        let mut stage = SystemStage::single(CameraPlugin::camera_look_system);
+        for frame in 1..=frames {
+            let delta = motion(frame);
+            if delta != Vec2::ZERO {
+                world
+                    .resource_mut::<Events<MouseMotion>>()
+                    .send(MouseMotion { delta });
+            }
+            world.resource_mut::<Time>().update_with_instant(
+                start + Duration::from_secs_f64(frame_time as f64 * frame as f64),
+            );
+            stage.run(&mut world);
+            world.resource_mut::<Events<MouseMotion>>().update();
+        }
+
+        world.get::<CustomCamera>(camera).unwrap().clone()
+    }
+
+    const START_YAW: f32 = -90.0;
+
+    /// Runs [`CameraPlugin::camera_look_system`] for `frames` frames of `frame_time` seconds, each
+    /// one getting `motion` from the mouse, then for another second without motion to let
+    /// smoothing settle
+    fn look_around(
+        settings: CameraControllerSettings,
+        frame_time: f32,
+        frames: u32,
+        motion: Vec2,
+    ) -> CustomCamera {
+        let settle_frames = (1.0 / frame_time).round() as u32;
+        run_look_system(settings, frame_time, frames + settle_frames, |frame| {
+            if frame <= frames {
+                motion
+            } else {
+                Vec2::ZERO
+            }
+        })
+    }
+
+    /// Sends the same total mouse motion at 15, 30, 60 and 144 fps
+    fn assert_same_orientation_at_any_frame_rate(settings: CameraControllerSettings) {
+        let total_motion = Vec2::new(120.0, -45.0);
+        let reference = look_around(settings.clone(), 1.0 / 60.0, 60, total_motion / 60.0);
+        assert!((reference.yaw - START_YAW.to_radians()).abs() > 0.1);
+        assert!(reference.pitch.abs() > 0.01);
+
+        for frames in [15, 30, 144] {
+            let camera = look_around(
+                settings.clone(),
+                1.0 / frames as f32,
+                frames,
+                total_motion / frames as f32,
+            );
+            assert!(
+                (camera.yaw - reference.yaw).abs() < 1e-5,
+                "yaw at {} fps: {} != {}",
+                frames,
+                camera.yaw,
+                reference.yaw
+            );
+            assert!(
+                (camera.pitch - reference.pitch).abs() < 1e-5,
+                "pitch at {} fps: {} != {}",
+                frames,
+                camera.pitch,
+                reference.pitch
+            );
+        }
+    }
+
+    #[test]
+    fn look_is_frame_rate_independent() {
+        assert_same_orientation_at_any_frame_rate(CameraControllerSettings::default());
+    }
+
+    #[test]
+    fn smoothed_look_is_frame_rate_independent() {
+        assert_same_orientation_at_any_frame_rate(CameraControllerSettings {
+            look_smoothing: 0.05,
+            ..default()
+        });
+    }
+
+    #[test]
+    fn smoothed_look_is_partially_applied_the_same_at_any_frame_rate() {
+        let settings = CameraControllerSettings {
+            look_smoothing: 0.05,
+            ..default()
+        };
+        let motion = Vec2::new(120.0, -45.0);
+        let settled = look_around(settings.clone(), 1.0 / 60.0, 1, motion);
+        let settled_yaw = settled.yaw - START_YAW.to_radians();
+
+        // A single burst of motion, looked at 0.1 seconds later while some of it is still pending
+        for fps in [30, 240] {
+            let camera = run_look_system(settings.clone(), 1.0 / fps as f32, fps / 10, |frame| {
+                if frame == 1 {
+                    motion
+                } else {
+                    Vec2::ZERO
+                }
+            });
+            let applied = (camera.yaw - START_YAW.to_radians()) / settled_yaw;
+            let expected = 1.0 - (-0.1_f32 / settings.look_smoothing).exp();
+            assert!(
+                (applied - expected).abs() < 1e-3,
+                "applied {} of the motion at {} fps instead of {}",
+                applied,
+                fps,
+                expected
+            );
+            assert!(
+                (camera.pitch - settled.pitch * expected).abs() < 1e-3,
+                "pitch at {} fps: {} != {}",
+                fps,
+                camera.pitch,
+                settled.pitch * expected
+            );
+        }
+    }
 }
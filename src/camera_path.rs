@@ -0,0 +1,422 @@
+use crate::{AppState, CameraAction, CameraControllerSettings, CameraOrientation, CustomCamera};
+use bevy::{
+    asset::{AssetLoader, LoadContext, LoadedAsset},
+    prelude::*,
+    reflect::TypeUuid,
+    utils::BoxedFuture,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    f32::consts::TAU,
+    fs, io,
+    ops::{Add, Mul, Sub},
+    path::Path,
+};
+
+/// Where recordings get saved, and played back from, relative to the assets folder
+pub const CAMERA_RECORDING_PATH: &str = "camera_paths/recording.campath.ron";
+
+/// Plays back [`CameraPath`]s on cameras with a [`CameraPathPlayer`], and records the first
+/// camera into a path with [`CameraAction::ToggleRecording`]
+pub struct CameraPathPlugin;
+
+impl Plugin for CameraPathPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_asset::<CameraPath>()
+            .init_asset_loader::<CameraPathLoader>()
+            .add_system_set(
+                SystemSet::on_update(AppState::Main)
+                    .with_system(Self::camera_path_input_system)
+                    .with_system(Self::camera_path_player_system)
+                    .with_system(Self::camera_path_recorder_system),
+            );
+    }
+}
+
+/// Where the camera is at `time` seconds into a [`CameraPath`]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct CameraKey {
+    pub time: f32,
+    pub position: Vec3,
+    pub yaw: f32,
+    pub pitch: f32,
+    pub fov: f32,
+}
+
+/// Keys of a camera flythrough, smoothly interpolated with a Catmull-Rom spline going through
+/// every key
+#[derive(Debug, Clone, Default, Serialize, Deserialize, TypeUuid)]
+#[uuid = "5c2c5d42-3fa8-4b5e-9f0e-4a4b9e1d7c21"]
+pub struct CameraPath {
+    /// Sorted by time
+    pub keys: Vec<CameraKey>,
+}
+
+impl CameraPath {
+    pub fn duration(&self) -> f32 {
+        self.keys.last().map_or(0.0, |key| key.time)
+    }
+
+    /// The interpolated key at `time`, clamped to the start and end of the path
+    pub fn sample(&self, time: f32) -> Option<CameraKey> {
+        let last = self.keys.len().checked_sub(1)?;
+        let next = self.keys.partition_point(|key| key.time <= time);
+        if next == 0 {
+            return Some(self.keys[0]);
+        }
+        if next > last {
+            return Some(self.keys[last]);
+        }
+
+        let i = next - 1;
+        let (k0, k1) = (&self.keys[i], &self.keys[next]);
+        let segment = k1.time - k0.time;
+        let s = (time - k0.time) / segment;
+
+        // Tangents at both ends of the segment, from the keys on either side of them
+        let tangent = |i: usize, value: fn(&CameraKey) -> Vec3| {
+            let before = &self.keys[i.saturating_sub(1)];
+            let after = &self.keys[(i + 1).min(last)];
+            (value(after) - value(before)) / (after.time - before.time) * segment
+        };
+        let position = hermite(
+            k0.position,
+            tangent(i, |key| key.position),
+            k1.position,
+            tangent(next, |key| key.position),
+            s,
+        );
+        let angles_of = |key: &CameraKey| Vec3::new(key.yaw, key.pitch, key.fov);
+        let angles = hermite(
+            angles_of(k0),
+            tangent(i, angles_of),
+            angles_of(k1),
+            tangent(next, angles_of),
+            s,
+        );
+
+        Some(CameraKey {
+            time,
+            position,
+            yaw: angles.x,
+            pitch: angles.y,
+            fov: angles.z,
+        })
+    }
+
+    /// Reads a path written by [`save`](Self::save), whose keys need increasing times
+    pub fn from_ron(bytes: &[u8]) -> Result<Self, bevy::asset::Error> {
+        let path: CameraPath = ron::de::from_bytes(bytes)?;
+        if path
+            .keys
+            .windows(2)
+            .any(|keys| keys[1].time <= keys[0].time)
+        {
+            return Err(bevy::asset::Error::msg(
+                "camera path keys must have increasing times",
+            ));
+        }
+        Ok(path)
+    }
+
+    /// Writes the path as RON, so it can be loaded back through the [`AssetServer`]
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let contents = ron::ser::to_string_pretty(self, default())
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+        fs::write(path, contents)
+    }
+}
+
+/// Cubic Hermite interpolation between `p0` and `p1` with tangents `m0` and `m1`
+fn hermite<T>(p0: T, m0: T, p1: T, m1: T, s: f32) -> T
+where
+    T: Copy + Add<Output = T> + Sub<Output = T> + Mul<f32, Output = T>,
+{
+    let s2 = s * s;
+    let s3 = s2 * s;
+    p0 * (2.0 * s3 - 3.0 * s2 + 1.0)
+        + m0 * (s3 - 2.0 * s2 + s)
+        + p1 * (-2.0 * s3 + 3.0 * s2)
+        + m1 * (s3 - s2)
+}
+
+#[derive(Default)]
+pub struct CameraPathLoader;
+
+impl AssetLoader for CameraPathLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            let path = CameraPath::from_ron(bytes)?;
+            load_context.set_default_asset(LoadedAsset::new(path));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["campath.ron"]
+    }
+}
+
+/// Drives the [`CustomCamera`] along a [`CameraPath`] instead of user input, and gets removed once
+/// the path is over unless it loops
+#[derive(Component, Debug, Clone)]
+pub struct CameraPathPlayer {
+    pub path: Handle<CameraPath>,
+    pub time: f32,
+    pub looping: bool,
+    /// Advance by this many seconds every frame instead of the frame time, so captures come out
+    /// the same whatever the frame rate
+    pub fixed_timestep: Option<f32>,
+}
+
+impl CameraPathPlayer {
+    pub fn new(path: Handle<CameraPath>) -> Self {
+        Self {
+            path,
+            time: 0.0,
+            looping: false,
+            fixed_timestep: None,
+        }
+    }
+}
+
+/// Samples the [`CustomCamera`] into a [`CameraPath`] every `interval` seconds
+#[derive(Component, Debug, Clone)]
+pub struct CameraPathRecorder {
+    pub interval: f32,
+    pub path: CameraPath,
+    time: f32,
+    next_sample: f32,
+}
+
+impl Default for CameraPathRecorder {
+    fn default() -> Self {
+        Self {
+            interval: 0.1,
+            path: default(),
+            time: 0.0,
+            next_sample: 0.0,
+        }
+    }
+}
+
+impl CameraPathPlugin {
+    /// Starts and stops recording the first camera, and plays the last recording back on it
+    fn camera_path_input_system(
+        mut commands: Commands,
+        cameras: Query<(Entity, Option<&CameraPathRecorder>), With<CustomCamera>>,
+        input: Res<Input<KeyCode>>,
+        settings: Res<CameraControllerSettings>,
+        asset_server: Res<AssetServer>,
+    ) {
+        let (camera, recorder) = match cameras.iter().next() {
+            Some(camera) => camera,
+            None => return,
+        };
+
+        if settings.just_pressed(&input, CameraAction::ToggleRecording) {
+            match recorder {
+                Some(recorder) => {
+                    let path = Path::new("assets").join(CAMERA_RECORDING_PATH);
+                    match recorder.path.save(&path) {
+                        Ok(()) => info!("Saved camera path to {}", path.display()),
+                        Err(error) => {
+                            error!(
+                                "Failed to save camera path to {}: {}",
+                                path.display(),
+                                error
+                            )
+                        }
+                    }
+                    commands.entity(camera).remove::<CameraPathRecorder>();
+                }
+                None => {
+                    commands
+                        .entity(camera)
+                        .insert(CameraPathRecorder::default());
+                }
+            }
+        }
+
+        if settings.just_pressed(&input, CameraAction::PlayRecording) && recorder.is_none() {
+            commands.entity(camera).insert(CameraPathPlayer::new(
+                asset_server.load(CAMERA_RECORDING_PATH),
+            ));
+        }
+    }
+
+    fn camera_path_player_system(
+        mut commands: Commands,
+        mut cameras: Query<(Entity, &mut CustomCamera, &mut CameraPathPlayer)>,
+        paths: Res<Assets<CameraPath>>,
+        time: Res<Time>,
+    ) {
+        for (entity, mut camera, mut player) in &mut cameras {
+            // Still loading
+            let path = match paths.get(&player.path) {
+                Some(path) => path,
+                None => continue,
+            };
+
+            let duration = path.duration();
+            if player.time > duration {
+                if player.looping && duration > 0.0 {
+                    player.time %= duration;
+                } else {
+                    commands.entity(entity).remove::<CameraPathPlayer>();
+                    continue;
+                }
+            }
+
+            if let Some(key) = path.sample(player.time) {
+                camera.set_orientation(CameraOrientation::Euler);
+                camera.position = key.position;
+                camera.yaw = key.yaw;
+                camera.pitch = key.pitch;
+                camera.fov = key.fov;
+            }
+
+            player.time += player
+                .fixed_timestep
+                .unwrap_or_else(|| time.delta_seconds());
+        }
+    }
+
+    fn camera_path_recorder_system(
+        mut cameras: Query<(&CustomCamera, &mut CameraPathRecorder)>,
+        time: Res<Time>,
+    ) {
+        for (camera, mut recorder) in &mut cameras {
+            if recorder.time >= recorder.next_sample {
+                let (mut yaw, pitch) = camera.yaw_pitch();
+                // Keep the yaw continuous so the spline doesn't spin around when it wraps
+                if let Some(previous) = recorder.path.keys.last() {
+                    yaw -= ((yaw - previous.yaw) / TAU).round() * TAU;
+                }
+
+                let key = CameraKey {
+                    time: recorder.time,
+                    position: camera.position,
+                    yaw,
+                    pitch,
+                    fov: camera.fov,
+                };
+                recorder.path.keys.push(key);
+                recorder.next_sample += recorder.interval;
+            }
+            recorder.time += time.delta_seconds();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(time: f32, position: Vec3, yaw: f32) -> CameraKey {
+        CameraKey {
+            time,
+            position,
+            yaw,
+            pitch: 0.1 * time,
+            fov: 45.0 + time,
+        }
+    }
+
+    fn path() -> CameraPath {
+        CameraPath {
+            keys: vec![
+                key(0.0, Vec3::ZERO, 0.0),
+                key(1.0, Vec3::new(2.0, 1.0, 0.0), 0.5),
+                key(2.5, Vec3::new(3.0, 0.0, -4.0), 1.5),
+                key(3.0, Vec3::new(1.0, -1.0, -6.0), 1.0),
+            ],
+        }
+    }
+
+    fn assert_same_key(actual: CameraKey, expected: CameraKey) {
+        assert!(
+            (actual.time - expected.time).abs() < 1e-5,
+            "{:?} != {:?}",
+            actual,
+            expected
+        );
+        assert!(
+            actual.position.abs_diff_eq(expected.position, 1e-5),
+            "{:?} != {:?}",
+            actual,
+            expected
+        );
+        assert!(
+            (actual.yaw - expected.yaw).abs() < 1e-5,
+            "{:?} != {:?}",
+            actual,
+            expected
+        );
+        assert!(
+            (actual.pitch - expected.pitch).abs() < 1e-5,
+            "{:?} != {:?}",
+            actual,
+            expected
+        );
+        assert!(
+            (actual.fov - expected.fov).abs() < 1e-5,
+            "{:?} != {:?}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn spline_goes_through_every_key() {
+        let path = path();
+        for key in &path.keys {
+            assert_same_key(path.sample(key.time).unwrap(), *key);
+        }
+        // And in between them it's somewhere else
+        let between = path.sample(0.5).unwrap();
+        assert!(!between.position.abs_diff_eq(path.keys[0].position, 0.1));
+        assert!(!between.position.abs_diff_eq(path.keys[1].position, 0.1));
+    }
+
+    #[test]
+    fn times_outside_the_path_are_clamped() {
+        let path = path();
+        let first = path.keys[0];
+        let last = *path.keys.last().unwrap();
+        assert_same_key(path.sample(-1.0).unwrap(), first);
+        assert_same_key(path.sample(path.duration() + 1.0).unwrap(), last);
+    }
+
+    #[test]
+    fn single_key_path_stays_on_it() {
+        let only = key(1.0, Vec3::new(1.0, 2.0, 3.0), 0.5);
+        let path = CameraPath { keys: vec![only] };
+        for time in [0.0, 1.0, 2.0] {
+            assert_same_key(path.sample(time).unwrap(), only);
+        }
+        assert!(CameraPath::default().sample(0.0).is_none());
+    }
+
+    #[test]
+    fn loader_rejects_keys_out_of_order() {
+        let ron = |path: &CameraPath| ron::ser::to_string(path).unwrap();
+        assert!(CameraPath::from_ron(ron(&path()).as_bytes()).is_ok());
+
+        let mut backwards = path();
+        backwards.keys.swap(1, 2);
+        assert!(CameraPath::from_ron(ron(&backwards).as_bytes()).is_err());
+
+        let mut repeated = path();
+        repeated.keys[2].time = repeated.keys[1].time;
+        assert!(CameraPath::from_ron(ron(&repeated).as_bytes()).is_err());
+    }
+}
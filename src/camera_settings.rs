@@ -0,0 +1,174 @@
+use bevy::{prelude::*, utils::HashMap};
+use serde::{Deserialize, Serialize};
+use std::{fs, io, path::Path};
+
+/// Where [`CameraControllerSettings`] get loaded from, relative to the working directory
+pub const CAMERA_SETTINGS_PATH: &str = "camera_settings.ron";
+
+/// Everything the camera controllers can be told to do with the keyboard
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum CameraAction {
+    Forward,
+    Backward,
+    Left,
+    Right,
+    Up,
+    Down,
+    Sprint,
+    /// Only rolls cameras with [`CameraOrientation::Quaternion`](crate::CameraOrientation::Quaternion)
+    RollLeft,
+    RollRight,
+    /// Held in orbit mode to pan instead of rotating
+    Pan,
+    /// Switches between free-fly and orbit mode
+    SwitchMode,
+    /// Switches between perspective and orthographic projection
+    SwitchProjection,
+    /// Switches between the clamped euler angles and the quaternion orientation that can roll
+    SwitchOrientation,
+    /// Starts recording the camera into a [`CameraPath`](crate::CameraPath), or stops and saves it
+    ToggleRecording,
+    PlayRecording,
+    /// Switches between culling [`MaterialInstances`](crate::MaterialInstances) on the CPU and on
+    /// the GPU
+    ToggleCullingMode,
+}
+
+impl CameraAction {
+    pub const ALL: [CameraAction; 16] = [
+        CameraAction::Forward,
+        CameraAction::Backward,
+        CameraAction::Left,
+        CameraAction::Right,
+        CameraAction::Up,
+        CameraAction::Down,
+        CameraAction::Sprint,
+        CameraAction::RollLeft,
+        CameraAction::RollRight,
+        CameraAction::Pan,
+        CameraAction::SwitchMode,
+        CameraAction::SwitchProjection,
+        CameraAction::SwitchOrientation,
+        CameraAction::ToggleRecording,
+        CameraAction::PlayRecording,
+        CameraAction::ToggleCullingMode,
+    ];
+
+    fn default_keys(self) -> Vec<KeyCode> {
+        match self {
+            CameraAction::Forward => vec![KeyCode::W],
+            CameraAction::Backward => vec![KeyCode::S],
+            CameraAction::Left => vec![KeyCode::A],
+            CameraAction::Right => vec![KeyCode::D],
+            CameraAction::Up => vec![KeyCode::Space],
+            CameraAction::Down => vec![KeyCode::LControl],
+            CameraAction::Sprint => vec![KeyCode::LShift],
+            CameraAction::RollLeft => vec![KeyCode::Q],
+            CameraAction::RollRight => vec![KeyCode::E],
+            CameraAction::Pan => vec![KeyCode::LAlt, KeyCode::RAlt],
+            CameraAction::SwitchMode => vec![KeyCode::Tab],
+            CameraAction::SwitchProjection => vec![KeyCode::P],
+            CameraAction::SwitchOrientation => vec![KeyCode::F],
+            CameraAction::ToggleRecording => vec![KeyCode::F9],
+            CameraAction::PlayRecording => vec![KeyCode::F10],
+            CameraAction::ToggleCullingMode => vec![KeyCode::F7],
+        }
+    }
+}
+
+/// Speeds, sensitivities and key bindings shared by every camera controller.
+///
+/// Any field missing from the settings file keeps its default value, and so does the binding of
+/// any action missing from `bindings`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CameraControllerSettings {
+    /// Free-fly speed in units per second
+    pub move_speed: f32,
+    /// Applied to `move_speed` while [`CameraAction::Sprint`] is held
+    pub sprint_multiplier: f32,
+    /// Radians per pixel of mouse motion
+    pub look_sensitivity: f32,
+    /// Time constant in seconds of the exponential smoothing of mouse motion, 0 turns it off
+    pub look_smoothing: f32,
+    /// Radians per second
+    pub roll_speed: f32,
+    /// Orbit mode pan per pixel of mouse motion, scaled by the distance to the focus point
+    pub pan_sensitivity: f32,
+    /// Degrees of field of view per mouse wheel step
+    pub zoom_sensitivity: f32,
+    /// Fraction of the orthographic view size per mouse wheel step
+    pub ortho_zoom_sensitivity: f32,
+    /// Fraction of the distance to the focus point covered per mouse wheel step in orbit mode
+    pub dolly_sensitivity: f32,
+    /// Moving the mouse up looks down
+    pub invert_y: bool,
+    pub bindings: HashMap<CameraAction, Vec<KeyCode>>,
+}
+
+impl Default for CameraControllerSettings {
+    fn default() -> Self {
+        Self {
+            move_speed: 2.5,
+            sprint_multiplier: 3.0,
+            look_sensitivity: 0.002,
+            look_smoothing: 0.0,
+            roll_speed: 1.5,
+            pan_sensitivity: 0.002,
+            zoom_sensitivity: 1.0,
+            ortho_zoom_sensitivity: 0.1,
+            dolly_sensitivity: 0.1,
+            invert_y: false,
+            bindings: CameraAction::ALL
+                .into_iter()
+                .map(|action| (action, action.default_keys()))
+                .collect(),
+        }
+    }
+}
+
+impl CameraControllerSettings {
+    /// Reads the settings from a RON file
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        let mut settings: Self = ron::from_str(&contents)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+        for action in CameraAction::ALL {
+            settings
+                .bindings
+                .entry(action)
+                .or_insert_with(|| action.default_keys());
+        }
+        Ok(settings)
+    }
+
+    /// Same as [`load`](Self::load) but falls back to the defaults, so a missing or broken file
+    /// never stops the app from starting
+    pub fn load_or_default(path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref();
+        match Self::load(path) {
+            Ok(settings) => settings,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => default(),
+            Err(error) => {
+                warn!(
+                    "Failed to load camera settings from {}, using the defaults: {}",
+                    path.display(),
+                    error
+                );
+                default()
+            }
+        }
+    }
+
+    fn keys(&self, action: CameraAction) -> &[KeyCode] {
+        self.bindings.get(&action).map_or(&[], Vec::as_slice)
+    }
+
+    pub fn pressed(&self, input: &Input<KeyCode>, action: CameraAction) -> bool {
+        input.any_pressed(self.keys(action).iter().copied())
+    }
+
+    pub fn just_pressed(&self, input: &Input<KeyCode>, action: CameraAction) -> bool {
+        input.any_just_pressed(self.keys(action).iter().copied())
+    }
+}
@@ -0,0 +1,194 @@
+use crate::{
+    DiffuseTexture, EmissionSettings, EmissionTexture, MaterialColors, MaterialInstance,
+    MaterialInstances, SpecularTexture,
+};
+use bevy::{prelude::*, render::view::VisibilitySystems, transform::TransformSystem};
+
+/// Lets the instances of a custom material entity be spawned as child entities, so they can be
+/// queried, animated, picked and despawned one by one.
+///
+/// Every frame the children of each [`ChildInstances`] entity get gathered into a
+/// [`MaterialInstances`]. The children of every parent sharing the same mesh, textures and
+/// material settings are batched together into the [`MaterialInstances`] of the first of those
+/// parents, so they all get drawn from a single instance buffer. The other parents are left
+/// without any instances.
+pub struct ChildInstancesPlugin;
+
+impl Plugin for ChildInstancesPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system_to_stage(
+            CoreStage::PostUpdate,
+            Self::gather_child_instances
+                .after(TransformSystem::TransformPropagate)
+                .after(VisibilitySystems::VisibilityPropagate)
+                .before(VisibilitySystems::CheckVisibility),
+        );
+    }
+}
+
+/// Marks a custom material entity whose instances are its children with an [`InstanceMaterial`]
+/// instead of a hand written [`MaterialInstances`]
+#[derive(Component, Debug, Default, Clone, Copy)]
+pub struct ChildInstances;
+
+/// Material parameters of an instance spawned as a child of a [`ChildInstances`] entity, which
+/// gets placed by its [`GlobalTransform`]. They mean the same as in a [`MaterialInstance`].
+#[derive(Component, Debug, Clone, Copy)]
+pub struct InstanceMaterial {
+    pub shininess: f32,
+    pub diffuse_tint: Color,
+    pub specular_tint: Color,
+    pub emission: f32,
+    pub uv_offset: Vec2,
+    pub uv_scale: Vec2,
+}
+
+impl Default for InstanceMaterial {
+    fn default() -> Self {
+        let instance = MaterialInstance::default();
+        Self {
+            shininess: instance.shininess,
+            diffuse_tint: instance.diffuse_tint,
+            specular_tint: instance.specular_tint,
+            emission: instance.emission,
+            uv_offset: instance.uv_offset,
+            uv_scale: instance.uv_scale,
+        }
+    }
+}
+
+/// The child entity behind each of the [`MaterialInstances`] of a [`ChildInstances`] entity, in
+/// the same order. They can be children of other parents batched together with it.
+#[derive(Component, Debug, Default, Clone, Deref)]
+pub struct InstanceEntities(pub Vec<Entity>);
+
+type ChildInstancesQuery<'a> = (
+    Entity,
+    Option<&'a Children>,
+    &'a Handle<Mesh>,
+    Option<&'a DiffuseTexture>,
+    Option<&'a SpecularTexture>,
+    Option<&'a EmissionTexture>,
+    Option<&'a MaterialColors>,
+    Option<&'a EmissionSettings>,
+    Option<&'a mut MaterialInstances>,
+    Option<&'a mut InstanceEntities>,
+);
+
+/// What the children of a [`ChildInstances`] entity need to share with another one's to be drawn
+/// together
+#[derive(PartialEq)]
+struct BatchKey {
+    mesh: Handle<Mesh>,
+    textures: [Option<Handle<Image>>; 3],
+    colors: Option<MaterialColors>,
+    emission: Option<EmissionSettings>,
+}
+
+/// The instances gathered from the children of every parent with the same [`BatchKey`], which go
+/// to the first of those parents
+struct Batch {
+    key: BatchKey,
+    parent: Entity,
+    instances: Vec<MaterialInstance>,
+    entities: Vec<Entity>,
+}
+
+impl ChildInstancesPlugin {
+    fn gather_child_instances(
+        mut commands: Commands,
+        mut parents: Query<ChildInstancesQuery, With<ChildInstances>>,
+        instances: Query<(
+            &GlobalTransform,
+            &InstanceMaterial,
+            Option<&ComputedVisibility>,
+        )>,
+    ) {
+        // Go through the parents in a fixed order, so each batch keeps going to the same one
+        let mut sorted_parents = parents.iter().collect::<Vec<_>>();
+        sorted_parents.sort_by_key(|(entity, ..)| *entity);
+
+        let mut batches: Vec<Batch> = Vec::new();
+        for (entity, children, mesh, diffuse, specular, emission, colors, emission_settings, ..) in
+            sorted_parents
+        {
+            let key = BatchKey {
+                mesh: mesh.clone_weak(),
+                textures: [
+                    diffuse.map(|texture| texture.clone_weak()),
+                    specular.map(|texture| texture.clone_weak()),
+                    emission.map(|texture| texture.clone_weak()),
+                ],
+                colors: colors.copied(),
+                emission: emission_settings.copied(),
+            };
+            let batch_index = match batches.iter().position(|batch| batch.key == key) {
+                Some(batch_index) => batch_index,
+                None => {
+                    batches.push(Batch {
+                        key,
+                        parent: entity,
+                        instances: Vec::new(),
+                        entities: Vec::new(),
+                    });
+                    batches.len() - 1
+                }
+            };
+            let batch = &mut batches[batch_index];
+
+            // Children that got hidden are left out
+            let visible_instances = children.into_iter().flatten().filter_map(|child| {
+                instances
+                    .get(*child)
+                    .ok()
+                    .filter(|(_, _, visibility)| {
+                        visibility.map_or(true, ComputedVisibility::is_visible_in_hierarchy)
+                    })
+                    .map(|(transform, material, _)| (*child, transform, material))
+            });
+            for (child, transform, material) in visible_instances {
+                batch.instances.push(MaterialInstance {
+                    transform: transform.compute_transform(),
+                    shininess: material.shininess,
+                    diffuse_tint: material.diffuse_tint,
+                    specular_tint: material.specular_tint,
+                    emission: material.emission,
+                    uv_offset: material.uv_offset,
+                    uv_scale: material.uv_scale,
+                });
+                batch.entities.push(child);
+            }
+        }
+
+        for (entity, .., material_instances, instance_entities) in &mut parents {
+            let (gathered_instances, gathered_entities) =
+                match batches.iter_mut().find(|batch| batch.parent == entity) {
+                    Some(batch) => (
+                        std::mem::take(&mut batch.instances),
+                        std::mem::take(&mut batch.entities),
+                    ),
+                    // Its children got batched into another parent
+                    None => (Vec::new(), Vec::new()),
+                };
+
+            // Only touch the components when something moved, so the instance buffer doesn't get
+            // rewritten every frame
+            match (material_instances, instance_entities) {
+                (Some(mut material_instances), Some(mut instance_entities)) => {
+                    if material_instances.0 != gathered_instances {
+                        material_instances.0 = gathered_instances;
+                    }
+                    if instance_entities.0 != gathered_entities {
+                        instance_entities.0 = gathered_entities;
+                    }
+                }
+                _ => {
+                    commands.entity(entity).insert_bundle((
+                        MaterialInstances(gathered_instances),
+                        InstanceEntities(gathered_entities),
+                    ));
+                }
+            }
+        }
+    }
+}
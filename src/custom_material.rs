@@ -1,6 +1,7 @@
 use crate::{
-    CustomCamera, DiffuseTexture, DirectionalLight, EmissionTexture, PointLightInstances,
-    PointLightMaterial, SpecularTexture, Spotlight,
+    CullingFrustum, CustomViewBindGroupLayout, DiffuseTexture, EmissionTexture, GpuCulledInstances,
+    GpuInstanceCulling, IndirectInstanceBuffer, InstanceCullingMode, InstanceCullingSettings,
+    InstanceCullingStats, SetCustomViewBindGroup, SpecularTexture,
 };
 use bevy::{
     core_pipeline::core_3d::Transparent3d,
@@ -13,44 +14,110 @@ use bevy::{
     render::{
         extract_component::{ExtractComponent, ExtractComponentPlugin},
         mesh::{GpuBufferInfo, MeshVertexBufferLayout},
+        primitives::Aabb,
         render_asset::RenderAssets,
         render_phase::{
             AddRenderCommand, DrawFunctions, EntityRenderCommand, RenderCommandResult, RenderPhase,
             SetItemPipeline, TrackedRenderPass,
         },
         render_resource::{
-            encase::UniformBuffer, BindGroup, BindGroupDescriptor, BindGroupEntry, BindGroupLayout,
+            BindGroup, BindGroupDescriptor, BindGroupEntry, BindGroupLayout,
             BindGroupLayoutDescriptor, BindGroupLayoutEntry, BindingResource, BindingType, Buffer,
-            BufferBindingType, BufferInitDescriptor, BufferSize, BufferUsages, CompareFunction,
-            DepthBiasState, DepthStencilState, FrontFace, PipelineCache, PolygonMode,
-            PrimitiveState, RenderPipelineDescriptor, SamplerBindingType, ShaderStages, ShaderType,
-            SpecializedMeshPipeline, SpecializedMeshPipelineError, SpecializedMeshPipelines,
-            StencilState, TextureFormat, TextureSampleType, TextureViewDimension, VertexAttribute,
+            BufferBindingType, BufferId, BufferUsages, BufferVec, CompareFunction, DepthBiasState,
+            DepthStencilState, Extent3d, FrontFace, PipelineCache, PolygonMode, PrimitiveState,
+            RenderPipelineDescriptor, Sampler, SamplerBindingType, SamplerDescriptor, SamplerId,
+            ShaderStages, ShaderType, SpecializedMeshPipeline, SpecializedMeshPipelineError,
+            SpecializedMeshPipelines, StencilState, TextureDescriptor, TextureDimension,
+            TextureFormat, TextureSampleType, TextureUsages, TextureView, TextureViewDescriptor,
+            TextureViewDimension, TextureViewId, UniformBuffer, VertexAttribute,
             VertexBufferLayout, VertexFormat, VertexStepMode,
         },
-        renderer::RenderDevice,
-        texture::FallbackImage,
+        renderer::{RenderDevice, RenderQueue},
         view::ExtractedView,
-        RenderApp, RenderStage,
+        Extract, RenderApp, RenderStage,
     },
+    utils::{HashMap, HashSet},
 };
 use bytemuck::{Pod, Zeroable};
 
-#[derive(Component, Debug, Clone, Copy)]
-#[repr(C)]
+#[derive(Component, Debug, Clone, Copy, PartialEq)]
 pub struct MaterialInstance {
-    pub position: Vec3,
-    pub rotation_x: f32,
-    pub rotation_y: f32,
-    pub rotation_z: f32,
+    pub transform: Transform,
     pub shininess: f32,
+    /// Multiplies the diffuse map
+    pub diffuse_tint: Color,
+    /// Multiplies the specular map
+    pub specular_tint: Color,
+    /// Multiplies the emission map, 0.0 switches it off for this instance
+    pub emission: f32,
+    /// Added to the texture coordinates after scaling them by `uv_scale`
+    pub uv_offset: Vec2,
+    pub uv_scale: Vec2,
+}
+
+impl Default for MaterialInstance {
+    fn default() -> Self {
+        Self {
+            transform: Transform::identity(),
+            shininess: 32.0,
+            diffuse_tint: Color::WHITE,
+            specular_tint: Color::WHITE,
+            emission: 1.0,
+            uv_offset: Vec2::ZERO,
+            uv_scale: Vec2::ONE,
+        }
+    }
+}
+
+impl MaterialInstance {
+    /// Builds an instance out of the Euler angles instances used to be described with, in
+    /// radians. They get applied in the same order as they used to: the Y rotation first, then X,
+    /// then Z which used to be ignored.
+    pub fn from_euler(
+        position: Vec3,
+        rotation_x: f32,
+        rotation_y: f32,
+        rotation_z: f32,
+        shininess: f32,
+    ) -> Self {
+        Self {
+            transform: Transform::from_translation(position).with_rotation(Quat::from_euler(
+                EulerRot::YXZ,
+                rotation_y,
+                rotation_x,
+                rotation_z,
+            )),
+            shininess,
+            ..default()
+        }
+    }
+
+    pub fn model_matrix(&self) -> Mat4 {
+        self.transform.compute_matrix()
+    }
+
+    /// Takes the normals into world space. Unlike the model matrix it keeps them perpendicular to
+    /// the surface when the instance is scaled non-uniformly.
+    pub fn normal_matrix(&self) -> Mat4 {
+        self.model_matrix().inverse().transpose()
+    }
 }
 
 #[derive(Component, Deref, DerefMut, Debug)]
 pub struct MaterialInstances(pub Vec<MaterialInstance>);
 
-// The array size needs to be kept in sync with the custom_mesh.wgsl shader
-const NR_POINT_LIGHTS: usize = 4;
+/// Index of the one of the [`MaterialInstances`] that gets drawn highlighted
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HighlightedInstance(pub usize);
+
+impl ExtractComponent for HighlightedInstance {
+    type Query = &'static HighlightedInstance;
+    type Filter = ();
+
+    fn extract_component(item: bevy::ecs::query::QueryItem<Self::Query>) -> Self {
+        *item
+    }
+}
 
 impl ExtractComponent for MaterialInstances {
     type Query = &'static MaterialInstances;
@@ -61,6 +128,83 @@ impl ExtractComponent for MaterialInstances {
     }
 }
 
+/// How the emission map of a custom material entity lights it up, entities without one use the
+/// defaults
+#[derive(Component, Debug, Clone, Copy, PartialEq)]
+pub struct EmissionSettings {
+    /// Multiplies the emission map, on top of the [`MaterialInstance::emission`] of each instance
+    pub strength: f32,
+    /// Only emit where the specular map is black, like the glowing wood but not steel of
+    /// LearnOpenGL's lighting maps exercise
+    pub mask_by_specular: bool,
+    /// Scrolls the emission map by this many UVs per second, for animated emissive panels
+    pub scroll: Vec2,
+}
+
+impl Default for EmissionSettings {
+    fn default() -> Self {
+        Self {
+            strength: 1.0,
+            mask_by_specular: false,
+            scroll: Vec2::ZERO,
+        }
+    }
+}
+
+#[derive(Component, Debug, Default, Clone, Copy, PartialEq, ShaderType)]
+pub struct EmissionUniform {
+    uv_offset: Vec2,
+    strength: f32,
+    mask_by_specular: u32,
+}
+
+type EmissionQuery<'a> = (Entity, Option<&'a EmissionSettings>);
+
+fn extract_emission(
+    mut commands: Commands,
+    time: Extract<Res<Time>>,
+    query: Extract<Query<EmissionQuery, With<CustomMaterial>>>,
+) {
+    let seconds = time.seconds_since_startup() as f32;
+    for (entity, settings) in query.iter() {
+        let settings = settings.copied().unwrap_or_default();
+        commands.get_or_spawn(entity).insert(EmissionUniform {
+            // Wrapped around so it doesn't lose precision as time goes on
+            uv_offset: (settings.scroll * seconds).fract(),
+            strength: settings.strength,
+            mask_by_specular: settings.mask_by_specular as u32,
+        });
+    }
+}
+
+/// Solid colors standing in for the maps a custom material entity has no texture for, or whose
+/// texture is still loading. Entities without one use the defaults.
+#[derive(Component, Debug, Clone, Copy, PartialEq)]
+pub struct MaterialColors {
+    pub diffuse: Color,
+    pub specular: Color,
+    pub emission: Color,
+}
+
+impl Default for MaterialColors {
+    fn default() -> Self {
+        Self {
+            diffuse: Color::WHITE,
+            specular: Color::rgb(0.5, 0.5, 0.5),
+            emission: Color::BLACK,
+        }
+    }
+}
+
+impl ExtractComponent for MaterialColors {
+    type Query = &'static MaterialColors;
+    type Filter = ();
+
+    fn extract_component(item: bevy::ecs::query::QueryItem<Self::Query>) -> Self {
+        *item
+    }
+}
+
 #[derive(Component, Clone, Copy)]
 #[repr(C)]
 pub struct CustomMaterial;
@@ -79,11 +223,16 @@ pub struct CustomMaterialPlugin;
 impl Plugin for CustomMaterialPlugin {
     fn build(&self, app: &mut App) {
         app.add_plugin(ExtractComponentPlugin::<CustomMaterial>::default())
-            .add_plugin(ExtractComponentPlugin::<MaterialInstances>::default());
+            .add_plugin(ExtractComponentPlugin::<MaterialInstances>::default())
+            .add_plugin(ExtractComponentPlugin::<HighlightedInstance>::default())
+            .add_plugin(ExtractComponentPlugin::<MaterialColors>::default());
         app.sub_app_mut(RenderApp)
             .add_render_command::<Transparent3d, DrawCustomMaterial>()
             .init_resource::<CustomMaterialPipeline>()
             .init_resource::<SpecializedMeshPipelines<CustomMaterialPipeline>>()
+            .init_resource::<CustomMaterialBuffers>()
+            .init_resource::<SolidColorTextures>()
+            .add_system_to_stage(RenderStage::Extract, extract_emission)
             .add_system_to_stage(RenderStage::Queue, queue_custom_material)
             .add_system_to_stage(RenderStage::Prepare, prepare_buffers);
     }
@@ -137,258 +286,460 @@ pub struct InstanceBuffer {
     pub buffer: Buffer,
     pub length: usize,
 }
+
+/// The instances of a custom material entity that are visible from each view, which get drawn
+/// instead of its whole [`InstanceBuffer`]
+#[derive(Component, Default)]
+pub struct ViewInstanceBuffers(pub HashMap<Entity, ViewInstanceBuffer>);
+
+pub enum ViewInstanceBuffer {
+    /// Culled on the CPU, drawn with a regular draw
+    Culled(InstanceBuffer),
+    /// Culled on the GPU, drawn with an indirect draw
+    Indirect(IndirectInstanceBuffer),
+}
+
+/// The textures of a custom material entity
 #[derive(Component, Debug)]
-pub struct UniformMeta {
+pub struct MaterialBindGroup {
     pub bind_group: BindGroup,
 }
 #[derive(Debug, Copy, Clone, Pod, Zeroable)]
 #[repr(C)]
-struct RenderMaterialInstance {
+pub(crate) struct RenderMaterialInstance {
     // These need to be arrays, otherwise we couldn't derive Pod due to padding with shininess
     model: [[f32; 4]; 4],
     normal: [[f32; 4]; 4],
     shininess: f32,
+    // 1.0 for the highlighted instance, 0.0 for the rest
+    highlight: f32,
+    emission: f32,
+    // Tints are linear colors
+    diffuse_tint: [f32; 4],
+    specular_tint: [f32; 4],
+    // Offset in xy, scale in zw
+    uv_transform: [f32; 4],
 }
 
-#[derive(Debug, Copy, Clone, ShaderType)]
-#[repr(C)]
-struct DirectionalLightSettings {
-    direction: Vec3,
-    ambient: Vec4,
-    diffuse: Vec4,
-    specular: Vec4,
+impl RenderMaterialInstance {
+    fn new(instance: &MaterialInstance, highlighted: bool) -> Self {
+        Self {
+            model: instance.model_matrix().to_cols_array_2d(),
+            normal: instance.normal_matrix().to_cols_array_2d(),
+            shininess: instance.shininess,
+            highlight: if highlighted { 1.0 } else { 0.0 },
+            emission: instance.emission,
+            diffuse_tint: instance.diffuse_tint.as_linear_rgba_f32(),
+            specular_tint: instance.specular_tint.as_linear_rgba_f32(),
+            uv_transform: [
+                instance.uv_offset.x,
+                instance.uv_offset.y,
+                instance.uv_scale.x,
+                instance.uv_scale.y,
+            ],
+        }
+    }
 }
 
-#[derive(Debug, Copy, Clone, ShaderType)]
-#[repr(C)]
-struct PointLightSettings {
-    position: Vec3,
-    constant: f32,
-    linear: f32,
-    quadratic: f32,
-    ambient: Vec4,
-    diffuse: Vec4,
-    specular: Vec4,
+/// Per instance vertex data that stays on the GPU between frames
+pub struct PersistentInstanceBuffer<T: Pod> {
+    buffer: BufferVec<T>,
+    // What was last uploaded, to tell whether the buffer needs writing to
+    data: Vec<T>,
 }
 
-#[derive(Debug, Copy, Clone, ShaderType)]
-#[repr(C)]
-struct SpotlightSettings {
-    direction: Vec3,
-    position: Vec3,
-    cutoff: f32,
-    outer_cutoff: f32,
-    ambient: Vec4,
-    diffuse: Vec4,
-    specular: Vec4,
-    constant: f32,
-    linear: f32,
-    quadratic: f32,
-}
-
-impl Default for PointLightSettings {
+impl<T: Pod> Default for PersistentInstanceBuffer<T> {
     fn default() -> Self {
+        Self::new(BufferUsages::VERTEX)
+    }
+}
+
+impl<T: Pod> PersistentInstanceBuffer<T> {
+    pub fn new(usage: BufferUsages) -> Self {
+        let mut buffer = BufferVec::new(usage);
+        buffer.set_label(Some("instance data buffer"));
         Self {
-            position: Vec3::splat(0.0),
-            constant: 1.0,
-            linear: 0.1,
-            quadratic: 0.01,
-            ambient: Vec4::splat(0.0),
-            diffuse: Vec4::splat(0.0),
-            specular: Vec4::splat(0.0),
+            buffer,
+            data: Vec::new(),
+        }
+    }
+
+    /// Uploads `data` if it's different from what the buffer already holds. The buffer only gets
+    /// reallocated when `data` doesn't fit in it anymore.
+    pub fn write(
+        &mut self,
+        data: Vec<T>,
+        render_device: &RenderDevice,
+        render_queue: &RenderQueue,
+    ) -> Option<InstanceBuffer> {
+        let changed =
+            bytemuck::cast_slice::<T, u8>(&data) != bytemuck::cast_slice::<T, u8>(&self.data);
+        if changed || self.buffer.buffer().is_none() {
+            self.buffer.clear();
+            for instance in &data {
+                self.buffer.push(*instance);
+            }
+            self.buffer.write_buffer(render_device, render_queue);
+            self.data = data;
+        }
+
+        self.buffer.buffer().map(|buffer| InstanceBuffer {
+            buffer: buffer.clone(),
+            length: self.data.len(),
+        })
+    }
+}
+
+/// Returns the cached bind group if it was created from the resources identified by `key`,
+/// otherwise creates and caches a new one
+pub(crate) fn cached_bind_group<K: PartialEq>(
+    cache: &mut Option<(K, BindGroup)>,
+    key: K,
+    create: impl FnOnce() -> BindGroup,
+) -> BindGroup {
+    match cache {
+        Some((cached_key, bind_group)) if *cached_key == key => bind_group.clone(),
+        _ => {
+            let bind_group = create();
+            *cache = Some((key, bind_group.clone()));
+            bind_group
         }
     }
 }
 
+/// 1x1 textures of a single color, bound in place of the maps a custom material entity doesn't
+/// have. One gets created for each color the [`MaterialColors`] use.
+pub struct SolidColorTextures {
+    sampler: Sampler,
+    textures: HashMap<[u8; 4], TextureView>,
+    // Colors looked up since the last `retain_used`, the rest get dropped then
+    used: HashSet<[u8; 4]>,
+}
+
+impl FromWorld for SolidColorTextures {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        Self {
+            sampler: render_device.create_sampler(&SamplerDescriptor::default()),
+            textures: default(),
+            used: default(),
+        }
+    }
+}
+
+impl SolidColorTextures {
+    pub fn get(
+        &mut self,
+        color: Color,
+        render_device: &RenderDevice,
+        render_queue: &RenderQueue,
+    ) -> (TextureView, Sampler) {
+        // Same sRGB encoding as the texture maps, so the shader sees the color it'd get from them
+        let texel = color
+            .as_rgba_f32()
+            .map(|channel| (channel.clamp(0.0, 1.0) * u8::MAX as f32).round() as u8);
+        self.used.insert(texel);
+        let texture_view = self.textures.entry(texel).or_insert_with(|| {
+            render_device
+                .create_texture_with_data(
+                    render_queue,
+                    &TextureDescriptor {
+                        label: Some("solid color texture"),
+                        size: Extent3d {
+                            width: 1,
+                            height: 1,
+                            depth_or_array_layers: 1,
+                        },
+                        mip_level_count: 1,
+                        sample_count: 1,
+                        dimension: TextureDimension::D2,
+                        format: TextureFormat::Rgba8UnormSrgb,
+                        usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
+                    },
+                    &texel,
+                )
+                .create_view(&TextureViewDescriptor::default())
+        });
+        (texture_view.clone(), self.sampler.clone())
+    }
+
+    fn retain_used(&mut self) {
+        let used = std::mem::take(&mut self.used);
+        self.textures.retain(|texel, _| used.contains(texel));
+    }
+}
+
+/// GPU buffers of the custom material that live across frames
+#[derive(Default)]
+pub struct CustomMaterialBuffers {
+    entities: HashMap<Entity, CustomMaterialEntityBuffers>,
+}
+
+/// Ids of the textures, samplers and emission uniform bound by a custom material bind group
+type CustomMaterialBindGroupKey = ([TextureViewId; 3], [SamplerId; 3], BufferId);
+
+struct CustomMaterialEntityBuffers {
+    /// Every instance, for the passes that don't cull them and for culling them on the GPU
+    instances: PersistentInstanceBuffer<RenderMaterialInstance>,
+    /// The instances visible from each view when culling on the CPU
+    views: HashMap<Entity, PersistentInstanceBuffer<RenderMaterialInstance>>,
+    /// The buffers culling the instances for each view when culling on the GPU
+    gpu_views: HashMap<Entity, GpuCulledInstances>,
+    emission: UniformBuffer<EmissionUniform>,
+    bind_group: Option<(CustomMaterialBindGroupKey, BindGroup)>,
+}
+
+type CustomMaterialQuery<'a> = (
+    Entity,
+    &'a MaterialInstances,
+    &'a Handle<Mesh>,
+    Option<&'a DiffuseTexture>,
+    Option<&'a SpecularTexture>,
+    Option<&'a EmissionTexture>,
+    Option<&'a MaterialColors>,
+    Option<&'a HighlightedInstance>,
+    Option<&'a Aabb>,
+    &'a EmissionUniform,
+);
+
 #[allow(clippy::too_many_arguments)]
 fn prepare_buffers(
     mut commands: Commands,
-    query: Query<
-        (
-            Entity,
-            &MaterialInstances,
-            &DiffuseTexture,
-            &SpecularTexture,
-            &EmissionTexture,
-        ),
-        With<CustomMaterial>,
-    >,
-    lights_query: Query<&PointLightInstances, With<PointLightMaterial>>,
-    camera: Res<CustomCamera>,
+    query: Query<CustomMaterialQuery, With<CustomMaterial>>,
+    views: Query<(Entity, Option<&CullingFrustum>), With<RenderPhase<Transparent3d>>>,
     render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
     pipeline: Res<CustomMaterialPipeline>,
     images: Res<RenderAssets<Image>>,
-    fallback_image: Res<FallbackImage>,
-    dir_light: Res<DirectionalLight>,
-    spot_light: Res<Spotlight>,
+    mut solid_colors: ResMut<SolidColorTextures>,
+    mut buffers: ResMut<CustomMaterialBuffers>,
+    meshes: Res<RenderAssets<Mesh>>,
+    pipeline_cache: Res<PipelineCache>,
+    settings: Res<InstanceCullingSettings>,
+    mut gpu_culling: ResMut<GpuInstanceCulling>,
+    stats: Option<Res<InstanceCullingStats>>,
 ) {
-    for (entity, instance_data, diff_tex, spec_tex, emission_tex) in &query {
+    gpu_culling.clear();
+    let cull_on_gpu =
+        settings.mode == InstanceCullingMode::Gpu && gpu_culling.is_available(&pipeline_cache);
+
+    let (mut visible_instances, mut total_instances) = (Some(0), 0);
+    for (
+        entity,
+        instance_data,
+        mesh_handle,
+        diff_tex,
+        spec_tex,
+        emission_tex,
+        colors,
+        highlighted,
+        aabb,
+        emission,
+    ) in &query
+    {
+        let entity_buffers =
+            buffers
+                .entities
+                .entry(entity)
+                .or_insert_with(|| CustomMaterialEntityBuffers {
+                    instances: PersistentInstanceBuffer::new(gpu_culling.instance_buffer_usage()),
+                    views: default(),
+                    gpu_views: default(),
+                    emission: default(),
+                    bind_group: None,
+                });
+
+        let models = instance_data
+            .iter()
+            .map(MaterialInstance::model_matrix)
+            .collect::<Vec<Mat4>>();
         let render_instance_data = instance_data
             .iter()
-            .map(|instance| {
-                let model = Mat4::from_translation(instance.position)
-                    * Mat4::from_rotation_y(instance.rotation_y)
-                    * Mat4::from_rotation_x(instance.rotation_x);
-                RenderMaterialInstance {
-                    model: model.to_cols_array_2d(),
-                    normal: model.inverse().transpose().to_cols_array_2d(),
-                    shininess: instance.shininess,
-                }
+            .enumerate()
+            .map(|(i, instance)| {
+                RenderMaterialInstance::new(instance, highlighted == Some(&HighlightedInstance(i)))
             })
             .collect::<Vec<RenderMaterialInstance>>();
 
-        let instance_buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
-            label: Some("instance data buffer"),
-            contents: bytemuck::cast_slice(render_instance_data.as_slice()),
-            usage: BufferUsages::VERTEX | BufferUsages::COPY_DST,
-        });
-        commands.entity(entity).insert(InstanceBuffer {
-            buffer: instance_buffer,
-            length: instance_data.len(),
-        });
+        let instance_count = render_instance_data.len();
+        let instance_buffer = match entity_buffers.instances.write(
+            render_instance_data.clone(),
+            &render_device,
+            &render_queue,
+        ) {
+            Some(instance_buffer) => instance_buffer,
+            None => continue,
+        };
 
-        // TODO: Figure out why the fallback image doesn't work
-        let diff_tex_image = images.get(diff_tex).unwrap_or(&fallback_image);
-        let spec_tex_image = images.get(spec_tex).unwrap_or(&fallback_image);
-        let emission_tex_image = images.get(emission_tex).unwrap_or(&fallback_image);
+        // Instances count as visible when they're inside the frustum of any of the views
+        total_instances += instance_count;
+        let mut visible_in_any_view = vec![false; instance_count];
+        let mut view_instance_buffers = ViewInstanceBuffers::default();
+        for (view, frustum) in &views {
+            // Let the compute pass cull the instances against the frustum of each view, which
+            // needs the mesh bounds and the number of vertices to draw
+            let gpu_culled = match (frustum, aabb, meshes.get(mesh_handle)) {
+                (Some(frustum), Some(aabb), Some(gpu_mesh)) if cull_on_gpu => {
+                    Some(entity_buffers.gpu_views.entry(view).or_default().prepare(
+                        &mut gpu_culling,
+                        &instance_buffer,
+                        std::mem::size_of::<RenderMaterialInstance>(),
+                        frustum,
+                        aabb,
+                        &gpu_mesh.buffer_info,
+                        &render_device,
+                        &render_queue,
+                    ))
+                }
+                _ => None,
+            };
+            if let Some(indirect_buffer) = gpu_culled {
+                visible_instances = None;
+                view_instance_buffers
+                    .0
+                    .insert(view, ViewInstanceBuffer::Indirect(indirect_buffer));
+                continue;
+            }
 
-        let view_buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
-            label: Some("view mat buffer"),
-            usage: BufferUsages::COPY_DST | BufferUsages::UNIFORM,
-            contents: bytemuck::cast_slice(&[camera.get_view()]),
+            // Otherwise only upload the instances inside the frustum of the view
+            let visible_instance_data = render_instance_data
+                .iter()
+                .zip(&models)
+                .zip(&mut visible_in_any_view)
+                .filter(|((_, model), _)| match (frustum, aabb) {
+                    (Some(frustum), Some(aabb)) => frustum.intersects_instance(aabb, model),
+                    // Without a frustum or bounds there's nothing to cull against
+                    _ => true,
+                })
+                .map(|((instance, _), visible)| {
+                    *visible = true;
+                    *instance
+                })
+                .collect::<Vec<RenderMaterialInstance>>();
+            if let Some(instance_buffer) = entity_buffers.views.entry(view).or_default().write(
+                visible_instance_data,
+                &render_device,
+                &render_queue,
+            ) {
+                view_instance_buffers
+                    .0
+                    .insert(view, ViewInstanceBuffer::Culled(instance_buffer));
+            }
+        }
+        visible_instances = visible_instances.map(|visible| {
+            visible
+                + visible_in_any_view
+                    .iter()
+                    .filter(|visible| **visible)
+                    .count()
         });
-        let proj_buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
-            label: Some("proj mat buffer"),
-            contents: bytemuck::cast_slice(&[camera.get_proj()]),
-            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+        entity_buffers.views.retain(|view, _| {
+            matches!(
+                view_instance_buffers.0.get(view),
+                Some(ViewInstanceBuffer::Culled(_))
+            )
         });
-
-        let mut spot_light_mat_buf = UniformBuffer::new(Vec::new());
-        spot_light_mat_buf
-            .write(&SpotlightSettings {
-                direction: camera.get_direction(),
-                position: camera.position,
-                cutoff: spot_light.cutoff.to_radians().cos(),
-                outer_cutoff: spot_light.outer_cutoff.to_radians().cos(),
-                ambient: spot_light.ambient,
-                diffuse: spot_light.diffuse,
-                specular: spot_light.specular,
-                constant: spot_light.constant,
-                linear: spot_light.linear,
-                quadratic: spot_light.quadratic,
-            })
-            .unwrap();
-        let spot_light_mat_buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
-            label: Some("spot light buffer"),
-            contents: spot_light_mat_buf.as_ref(),
-            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+        entity_buffers.gpu_views.retain(|view, _| {
+            matches!(
+                view_instance_buffers.0.get(view),
+                Some(ViewInstanceBuffer::Indirect(_))
+            )
         });
+        commands
+            .entity(entity)
+            .insert_bundle((instance_buffer, view_instance_buffers));
 
-        let mut dir_light_mat_buf = UniformBuffer::new(Vec::new());
-        dir_light_mat_buf
-            .write(&DirectionalLightSettings {
-                direction: dir_light.direction,
-                ambient: dir_light.ambient,
-                diffuse: dir_light.diffuse,
-                specular: dir_light.specular,
-            })
-            .unwrap();
-        let dir_light_mat_buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
-            label: Some("light color buffer"),
-            contents: dir_light_mat_buf.as_ref(),
-            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
-        });
+        // Missing maps, and ones that are still loading, get replaced by a solid color
+        let colors = colors.copied().unwrap_or_default();
+        let mut texture_or_color = |texture: Option<&Handle<Image>>, color| match texture
+            .and_then(|texture| images.get(texture))
+        {
+            Some(image) => (image.texture_view.clone(), image.sampler.clone()),
+            None => solid_colors.get(color, &render_device, &render_queue),
+        };
+        let (diff_tex_view, diff_tex_sampler) =
+            texture_or_color(diff_tex.map(|texture| &**texture), colors.diffuse);
+        let (spec_tex_view, spec_tex_sampler) =
+            texture_or_color(spec_tex.map(|texture| &**texture), colors.specular);
+        let (emission_tex_view, emission_tex_sampler) =
+            texture_or_color(emission_tex.map(|texture| &**texture), colors.emission);
 
-        let mut point_lights = [PointLightSettings::default(); NR_POINT_LIGHTS];
-        if let Ok(light_instances) = lights_query.get_single() {
-            for (i, instance) in light_instances.iter().enumerate() {
-                if i < 4 {
-                    point_lights[i] = PointLightSettings {
-                        position: instance.position,
-                        constant: instance.constant,
-                        linear: instance.linear,
-                        quadratic: instance.quadratic,
-                        ambient: instance.ambient,
-                        diffuse: instance.diffuse,
-                        specular: instance.specular,
-                    };
-                }
-            }
+        // Only upload the emission uniform when it changes, which is every frame while scrolling
+        if entity_buffers.emission.buffer().is_none() || entity_buffers.emission.get() != emission {
+            entity_buffers.emission.set(*emission);
+            entity_buffers
+                .emission
+                .write_buffer(&render_device, &render_queue);
         }
-        let mut point_lights_mat_buf = UniformBuffer::new(Vec::new());
-        point_lights_mat_buf.write(&point_lights).unwrap();
+        let emission_buffer = entity_buffers.emission.buffer().unwrap();
 
-        let point_light_mat_buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
-            label: Some("light color buffer"),
-            contents: point_lights_mat_buf.as_ref(),
-            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
-        });
-        let view_pos_buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
-            label: Some("view pos buffer"),
-            contents: bytemuck::cast_slice(&[camera.position]),
-            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
-        });
-        let bind_group = render_device.create_bind_group(&BindGroupDescriptor {
-            label: Some("custom material uniform bind group"),
-            layout: &pipeline.bind_group_layout,
-            entries: &[
-                BindGroupEntry {
-                    binding: 0,
-                    resource: view_buffer.as_entire_binding(),
-                },
-                BindGroupEntry {
-                    binding: 1,
-                    resource: proj_buffer.as_entire_binding(),
-                },
-                BindGroupEntry {
-                    binding: 2,
-                    resource: BindingResource::TextureView(&diff_tex_image.texture_view),
-                },
-                BindGroupEntry {
-                    binding: 3,
-                    resource: BindingResource::Sampler(&diff_tex_image.sampler),
-                },
-                BindGroupEntry {
-                    binding: 4,
-                    resource: BindingResource::TextureView(&spec_tex_image.texture_view),
-                },
-                BindGroupEntry {
-                    binding: 5,
-                    resource: BindingResource::Sampler(&spec_tex_image.sampler),
-                },
-                BindGroupEntry {
-                    binding: 6,
-                    resource: BindingResource::TextureView(&emission_tex_image.texture_view),
-                },
-                BindGroupEntry {
-                    binding: 7,
-                    resource: BindingResource::Sampler(&emission_tex_image.sampler),
-                },
-                BindGroupEntry {
-                    binding: 8,
-                    resource: view_pos_buffer.as_entire_binding(),
-                },
-                BindGroupEntry {
-                    binding: 9,
-                    resource: dir_light_mat_buffer.as_entire_binding(),
-                },
-                BindGroupEntry {
-                    binding: 10,
-                    resource: point_light_mat_buffer.as_entire_binding(),
-                },
-                BindGroupEntry {
-                    binding: 11,
-                    resource: spot_light_mat_buffer.as_entire_binding(),
-                },
+        let key = (
+            [
+                diff_tex_view.id(),
+                spec_tex_view.id(),
+                emission_tex_view.id(),
+            ],
+            [
+                diff_tex_sampler.id(),
+                spec_tex_sampler.id(),
+                emission_tex_sampler.id(),
             ],
+            emission_buffer.id(),
+        );
+        let bind_group = cached_bind_group(&mut entity_buffers.bind_group, key, || {
+            render_device.create_bind_group(&BindGroupDescriptor {
+                label: Some("custom material textures bind group"),
+                layout: &pipeline.bind_group_layout,
+                entries: &[
+                    BindGroupEntry {
+                        binding: 0,
+                        resource: BindingResource::TextureView(&diff_tex_view),
+                    },
+                    BindGroupEntry {
+                        binding: 1,
+                        resource: BindingResource::Sampler(&diff_tex_sampler),
+                    },
+                    BindGroupEntry {
+                        binding: 2,
+                        resource: BindingResource::TextureView(&spec_tex_view),
+                    },
+                    BindGroupEntry {
+                        binding: 3,
+                        resource: BindingResource::Sampler(&spec_tex_sampler),
+                    },
+                    BindGroupEntry {
+                        binding: 4,
+                        resource: BindingResource::TextureView(&emission_tex_view),
+                    },
+                    BindGroupEntry {
+                        binding: 5,
+                        resource: BindingResource::Sampler(&emission_tex_sampler),
+                    },
+                    BindGroupEntry {
+                        binding: 6,
+                        resource: emission_buffer.as_entire_binding(),
+                    },
+                ],
+            })
         });
-        commands.entity(entity).insert(UniformMeta { bind_group });
+        commands
+            .entity(entity)
+            .insert(MaterialBindGroup { bind_group });
+    }
+
+    // Drop the buffers of entities that went away
+    buffers.entities.retain(|entity, _| query.contains(*entity));
+    solid_colors.retain_used();
+
+    if let Some(stats) = stats {
+        stats.set(visible_instances, total_instances);
     }
 }
 
 pub struct CustomMaterialPipeline {
     shader: Handle<Shader>,
     mesh_pipeline: MeshPipeline,
+    view_layout: CustomViewBindGroupLayout,
     bind_group_layout: BindGroupLayout,
 }
 
@@ -400,33 +751,14 @@ impl FromWorld for CustomMaterialPipeline {
         let shader = asset_server.load("shaders/custom_mesh.wgsl");
 
         let mesh_pipeline = world.resource::<MeshPipeline>();
+        let view_layout = world.resource::<CustomViewBindGroupLayout>();
 
         let bind_group_layout =
             render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
-                label: Some("Uniforms"),
+                label: Some("Custom material textures"),
                 entries: &[
                     BindGroupLayoutEntry {
                         binding: 0,
-                        visibility: ShaderStages::VERTEX,
-                        ty: BindingType::Buffer {
-                            ty: BufferBindingType::Uniform,
-                            has_dynamic_offset: false,
-                            min_binding_size: BufferSize::new(std::mem::size_of::<Mat4>() as u64),
-                        },
-                        count: None,
-                    },
-                    BindGroupLayoutEntry {
-                        binding: 1,
-                        visibility: ShaderStages::VERTEX,
-                        ty: BindingType::Buffer {
-                            ty: BufferBindingType::Uniform,
-                            has_dynamic_offset: false,
-                            min_binding_size: BufferSize::new(std::mem::size_of::<Mat4>() as u64),
-                        },
-                        count: None,
-                    },
-                    BindGroupLayoutEntry {
-                        binding: 2,
                         visibility: ShaderStages::FRAGMENT,
                         ty: BindingType::Texture {
                             multisampled: false,
@@ -436,13 +768,13 @@ impl FromWorld for CustomMaterialPipeline {
                         count: None,
                     },
                     BindGroupLayoutEntry {
-                        binding: 3,
+                        binding: 1,
                         visibility: ShaderStages::FRAGMENT,
                         ty: BindingType::Sampler(SamplerBindingType::Filtering),
                         count: None,
                     },
                     BindGroupLayoutEntry {
-                        binding: 4,
+                        binding: 2,
                         visibility: ShaderStages::FRAGMENT,
                         ty: BindingType::Texture {
                             multisampled: false,
@@ -452,13 +784,13 @@ impl FromWorld for CustomMaterialPipeline {
                         count: None,
                     },
                     BindGroupLayoutEntry {
-                        binding: 5,
+                        binding: 3,
                         visibility: ShaderStages::FRAGMENT,
                         ty: BindingType::Sampler(SamplerBindingType::Filtering),
                         count: None,
                     },
                     BindGroupLayoutEntry {
-                        binding: 6,
+                        binding: 4,
                         visibility: ShaderStages::FRAGMENT,
                         ty: BindingType::Texture {
                             multisampled: false,
@@ -468,53 +800,18 @@ impl FromWorld for CustomMaterialPipeline {
                         count: None,
                     },
                     BindGroupLayoutEntry {
-                        binding: 7,
+                        binding: 5,
                         visibility: ShaderStages::FRAGMENT,
                         ty: BindingType::Sampler(SamplerBindingType::Filtering),
                         count: None,
                     },
                     BindGroupLayoutEntry {
-                        binding: 8,
-                        visibility: ShaderStages::FRAGMENT,
-                        ty: BindingType::Buffer {
-                            ty: BufferBindingType::Uniform,
-                            has_dynamic_offset: false,
-                            min_binding_size: BufferSize::new(
-                                std::mem::size_of::<[f32; 3]>() as u64
-                            ),
-                        },
-                        count: None,
-                    },
-                    BindGroupLayoutEntry {
-                        binding: 9,
-                        visibility: ShaderStages::FRAGMENT,
-                        ty: BindingType::Buffer {
-                            ty: BufferBindingType::Uniform,
-                            has_dynamic_offset: false,
-                            min_binding_size: Some(DirectionalLightSettings::min_size()),
-                        },
-                        count: None,
-                    },
-                    BindGroupLayoutEntry {
-                        binding: 10,
-                        visibility: ShaderStages::FRAGMENT,
-                        ty: BindingType::Buffer {
-                            ty: BufferBindingType::Uniform,
-                            has_dynamic_offset: false,
-                            min_binding_size: BufferSize::new(
-                                (std::mem::size_of::<PointLightSettings>() * NR_POINT_LIGHTS)
-                                    as u64,
-                            ),
-                        },
-                        count: None,
-                    },
-                    BindGroupLayoutEntry {
-                        binding: 11,
+                        binding: 6,
                         visibility: ShaderStages::FRAGMENT,
                         ty: BindingType::Buffer {
                             ty: BufferBindingType::Uniform,
                             has_dynamic_offset: false,
-                            min_binding_size: Some(SpotlightSettings::min_size()),
+                            min_binding_size: Some(EmissionUniform::min_size()),
                         },
                         count: None,
                     },
@@ -524,6 +821,7 @@ impl FromWorld for CustomMaterialPipeline {
         CustomMaterialPipeline {
             shader,
             mesh_pipeline: mesh_pipeline.clone(),
+            view_layout: view_layout.clone(),
             bind_group_layout,
         }
     }
@@ -579,9 +877,11 @@ impl SpecializedMeshPipeline for CustomMaterialPipeline {
                     offset: VertexFormat::Float32x4.size() * 6,
                     shader_location: 9,
                 },
+                // The last column of the normal matrix is skipped, the shader drops it anyway
+                // and there can only be 16 vertex attributes
                 VertexAttribute {
-                    format: VertexFormat::Float32x4,
-                    offset: VertexFormat::Float32x4.size() * 7,
+                    format: VertexFormat::Float32,
+                    offset: VertexFormat::Float32x4.size() * 8 + VertexFormat::Float32.size() * 2,
                     shader_location: 10,
                 },
                 VertexAttribute {
@@ -589,12 +889,48 @@ impl SpecializedMeshPipeline for CustomMaterialPipeline {
                     offset: VertexFormat::Float32x4.size() * 8,
                     shader_location: 11,
                 },
+                VertexAttribute {
+                    format: VertexFormat::Float32,
+                    offset: VertexFormat::Float32x4.size() * 8 + VertexFormat::Float32.size(),
+                    shader_location: 12,
+                },
+                VertexAttribute {
+                    format: VertexFormat::Float32x4,
+                    offset: VertexFormat::Float32x4.size() * 8 + VertexFormat::Float32.size() * 3,
+                    shader_location: 13,
+                },
+                VertexAttribute {
+                    format: VertexFormat::Float32x4,
+                    offset: VertexFormat::Float32x4.size() * 9 + VertexFormat::Float32.size() * 3,
+                    shader_location: 14,
+                },
+                VertexAttribute {
+                    format: VertexFormat::Float32x4,
+                    offset: VertexFormat::Float32x4.size() * 10 + VertexFormat::Float32.size() * 3,
+                    shader_location: 15,
+                },
             ],
         });
-        descriptor.fragment.as_mut().unwrap().shader = self.shader.clone();
+        let fragment = descriptor.fragment.as_mut().unwrap();
+        fragment.shader = self.shader.clone();
+        if self.view_layout.point_light_binding_type == BufferBindingType::Uniform {
+            descriptor
+                .vertex
+                .shader_defs
+                .push(String::from("NO_STORAGE_BUFFERS_SUPPORT"));
+            fragment
+                .shader_defs
+                .push(String::from("NO_STORAGE_BUFFERS_SUPPORT"));
+        }
+        if self.view_layout.point_shadow_view_dimension == TextureViewDimension::Cube {
+            fragment
+                .shader_defs
+                .push(String::from("NO_CUBE_ARRAY_TEXTURES_SUPPORT"));
+        }
         descriptor.layout = Some(vec![
             self.mesh_pipeline.view_layout.clone(),
             self.mesh_pipeline.mesh_layout.clone(),
+            self.view_layout.layout.clone(),
             self.bind_group_layout.clone(),
         ]);
         descriptor.label = Some("Custom Mesh pipeline descriptor".into());
@@ -610,7 +946,9 @@ impl SpecializedMeshPipeline for CustomMaterialPipeline {
         descriptor.depth_stencil = Some(DepthStencilState {
             format: TextureFormat::Depth32Float,
             depth_write_enabled: true,
-            depth_compare: CompareFunction::Less,
+            // CustomCamera projects to reverse-Z like bevy's cameras, the depth buffer gets
+            // cleared to 0.0 at the far plane and nearer fragments have greater depth
+            depth_compare: CompareFunction::Greater,
             stencil: StencilState::default(),
             bias: DepthBiasState::default(),
         });
@@ -623,7 +961,8 @@ type DrawCustomMaterial = (
     SetItemPipeline,
     SetMeshViewBindGroup<0>,
     SetMeshBindGroup<1>,
-    SetUniformsBindGroup<2>,
+    SetCustomViewBindGroup<2>,
+    SetMaterialBindGroup<3>,
     DrawMeshInstanced,
 );
 
@@ -633,24 +972,46 @@ impl EntityRenderCommand for DrawMeshInstanced {
     type Param = (
         SRes<RenderAssets<Mesh>>,
         SQuery<Read<Handle<Mesh>>>,
-        SQuery<Read<InstanceBuffer>>,
+        SQuery<(Read<InstanceBuffer>, Option<Read<ViewInstanceBuffers>>)>,
     );
     #[inline]
     fn render<'w>(
-        _view: Entity,
+        view: Entity,
         item: Entity,
         (meshes, mesh_query, instance_buffer_query): SystemParamItem<'w, '_, Self::Param>,
         pass: &mut TrackedRenderPass<'w>,
     ) -> RenderCommandResult {
         let mesh_handle = mesh_query.get(item).unwrap();
-        if let Ok(instance_buffer) = instance_buffer_query.get_inner(item) {
+        if let Ok((instance_buffer, view_instance_buffers)) = instance_buffer_query.get_inner(item)
+        {
+            // A view without a buffer of its own had every instance culled
+            let (instance_buffer, indirect_buffer) = match view_instance_buffers
+                .map(|buffers| buffers.0.get(&view))
+            {
+                Some(Some(ViewInstanceBuffer::Culled(instance_buffer))) => (instance_buffer, None),
+                Some(Some(ViewInstanceBuffer::Indirect(indirect_buffer))) => {
+                    (instance_buffer, Some(indirect_buffer))
+                }
+                Some(None) => return RenderCommandResult::Success,
+                None => (instance_buffer, None),
+            };
+            if indirect_buffer.is_none() && instance_buffer.length == 0 {
+                return RenderCommandResult::Success;
+            }
             let gpu_mesh = match meshes.into_inner().get(mesh_handle) {
                 Some(gpu_mesh) => gpu_mesh,
                 None => return RenderCommandResult::Failure,
             };
 
             pass.set_vertex_buffer(0, gpu_mesh.vertex_buffer.slice(..));
-            pass.set_vertex_buffer(1, instance_buffer.buffer.slice(..));
+            pass.set_vertex_buffer(
+                1,
+                indirect_buffer
+                    .map_or(&instance_buffer.buffer, |indirect_buffer| {
+                        &indirect_buffer.buffer
+                    })
+                    .slice(..),
+            );
 
             match &gpu_mesh.buffer_info {
                 GpuBufferInfo::Indexed {
@@ -659,11 +1020,20 @@ impl EntityRenderCommand for DrawMeshInstanced {
                     count,
                 } => {
                     pass.set_index_buffer(buffer.slice(..), 0, *index_format);
-                    pass.draw_indexed(0..*count, 0, 0..instance_buffer.length as u32);
-                }
-                GpuBufferInfo::NonIndexed { vertex_count } => {
-                    pass.draw(0..*vertex_count, 0..instance_buffer.length as u32);
+                    match indirect_buffer {
+                        // The compute pass wrote how many instances are visible
+                        Some(indirect_buffer) => {
+                            pass.draw_indexed_indirect(&indirect_buffer.indirect, 0);
+                        }
+                        None => {
+                            pass.draw_indexed(0..*count, 0, 0..instance_buffer.length as u32);
+                        }
+                    }
                 }
+                GpuBufferInfo::NonIndexed { vertex_count } => match indirect_buffer {
+                    Some(indirect_buffer) => pass.draw_indirect(&indirect_buffer.indirect, 0),
+                    None => pass.draw(0..*vertex_count, 0..instance_buffer.length as u32),
+                },
             }
         }
 
@@ -671,21 +1041,76 @@ impl EntityRenderCommand for DrawMeshInstanced {
     }
 }
 
-struct SetUniformsBindGroup<const I: usize>;
+struct SetMaterialBindGroup<const I: usize>;
 
-impl<const I: usize> EntityRenderCommand for SetUniformsBindGroup<I> {
-    type Param = SQuery<Read<UniformMeta>>;
+impl<const I: usize> EntityRenderCommand for SetMaterialBindGroup<I> {
+    type Param = SQuery<Read<MaterialBindGroup>>;
 
     fn render<'w>(
         _view: Entity,
         item: Entity,
-        uniforms_query: SystemParamItem<'w, '_, Self::Param>,
+        material_query: SystemParamItem<'w, '_, Self::Param>,
         pass: &mut TrackedRenderPass<'w>,
     ) -> RenderCommandResult {
-        if let Ok(uniforms_meta) = uniforms_query.get_inner(item) {
-            pass.set_bind_group(I, &uniforms_meta.bind_group, &[]);
+        if let Ok(material_bind_group) = material_query.get_inner(item) {
+            pass.set_bind_group(I, &material_bind_group.bind_group, &[]);
         };
 
         RenderCommandResult::Success
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scaled_instance() -> MaterialInstance {
+        MaterialInstance {
+            transform: Transform {
+                translation: Vec3::new(1.0, -2.0, 3.0),
+                rotation: Quat::from_euler(EulerRot::YXZ, 0.3, -1.2, 0.7),
+                scale: Vec3::new(0.5, 2.0, 3.0),
+            },
+            ..default()
+        }
+    }
+
+    #[test]
+    fn instance_matrices_match_transform() {
+        let instance = scaled_instance();
+        let render_instance = RenderMaterialInstance::new(&instance, false);
+
+        let model = instance.transform.compute_matrix();
+        assert_eq!(Mat4::from_cols_array_2d(&render_instance.model), model);
+        assert!(Mat4::from_cols_array_2d(&render_instance.normal)
+            .abs_diff_eq(model.inverse().transpose(), 1e-5));
+    }
+
+    #[test]
+    fn normals_stay_perpendicular_with_non_uniform_scale() {
+        let instance = scaled_instance();
+        let model = instance.model_matrix();
+        let normal_matrix = instance.normal_matrix();
+
+        // A surface with this normal contains both tangents
+        let normal = Vec3::new(1.0, 1.0, 0.0).normalize();
+        for tangent in [Vec3::new(1.0, -1.0, 0.0), Vec3::Z] {
+            let world_normal = normal_matrix.transform_vector3(normal);
+            let world_tangent = model.transform_vector3(tangent);
+            assert!(world_normal.dot(world_tangent).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn euler_angles_migrate_to_the_same_rotation() {
+        let (x, y) = (0.4, -1.1);
+        let instance = MaterialInstance::from_euler(Vec3::new(4.0, 5.0, 6.0), x, y, 0.0, 8.0);
+
+        // How the model matrix used to be built
+        let euler_model = Mat4::from_translation(Vec3::new(4.0, 5.0, 6.0))
+            * Mat4::from_rotation_y(y)
+            * Mat4::from_rotation_x(x);
+        assert!(instance.model_matrix().abs_diff_eq(euler_model, 1e-5));
+        assert_eq!(instance.shininess, 8.0);
+    }
+}
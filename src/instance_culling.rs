@@ -0,0 +1,513 @@
+use crate::{
+    cached_bind_group, CameraAction, CameraControllerSettings, CustomCamera, CustomMaterial,
+    InstanceBuffer, MaterialInstances,
+};
+use bevy::{
+    diagnostic::{Diagnostic, DiagnosticId, Diagnostics},
+    prelude::*,
+    render::{
+        extract_resource::{ExtractResource, ExtractResourcePlugin},
+        mesh::GpuBufferInfo,
+        primitives::{Aabb, Frustum},
+        render_graph::{Node, NodeRunError, RenderGraph, RenderGraphContext},
+        render_resource::{
+            BindGroup, BindGroupDescriptor, BindGroupEntry, BindGroupLayout,
+            BindGroupLayoutDescriptor, BindGroupLayoutEntry, BindingResource, BindingType, Buffer,
+            BufferBinding, BufferBindingType, BufferDescriptor, BufferId, BufferUsages,
+            CachedComputePipelineId, ComputePassDescriptor, ComputePipelineDescriptor,
+            PipelineCache, ShaderStages, ShaderType, UniformBuffer,
+        },
+        renderer::{RenderContext, RenderDevice, RenderQueue},
+        view::VisibilitySystems,
+        Extract, RenderApp, RenderStage,
+    },
+    utils::HashSet,
+};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+pub const INSTANCE_CULLING_PASS: &str = "instance_culling_pass";
+// Needs to be kept in sync with the instance_culling.wgsl shader
+const INSTANCE_CULLING_WORKGROUP_SIZE: u32 = 64;
+
+/// Gives the render world what it needs to cull [`MaterialInstances`] one by one against the
+/// frustum of every [`CustomCamera`], and reports how many of them are visible
+pub struct InstanceCullingPlugin;
+
+impl Plugin for InstanceCullingPlugin {
+    fn build(&self, app: &mut App) {
+        let stats = InstanceCullingStats::default();
+        app.init_resource::<InstanceCullingSettings>()
+            .add_plugin(ExtractResourcePlugin::<InstanceCullingSettings>::default())
+            .insert_resource(stats.clone())
+            .add_startup_system(Self::setup_diagnostics_system)
+            .add_system(Self::diagnostics_system)
+            .add_system(Self::culling_mode_input_system)
+            .add_system_to_stage(
+                CoreStage::PostUpdate,
+                Self::calculate_instance_bounds.before(VisibilitySystems::CheckVisibility),
+            );
+        let render_app = app.sub_app_mut(RenderApp);
+        render_app
+            .insert_resource(stats)
+            .init_resource::<GpuInstanceCulling>()
+            .add_system_to_stage(RenderStage::Extract, extract_instance_culling);
+
+        let mut graph = render_app.world.resource_mut::<RenderGraph>();
+        graph.add_node(INSTANCE_CULLING_PASS, InstanceCullingNode);
+        graph
+            .add_node_edge(
+                INSTANCE_CULLING_PASS,
+                bevy::render::main_graph::node::CAMERA_DRIVER,
+            )
+            .unwrap();
+    }
+}
+
+/// Where the [`MaterialInstances`] get culled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InstanceCullingMode {
+    /// In `prepare_buffers`, uploading only the visible instances of every view
+    Cpu,
+    /// In a compute pass that compacts the visible instances and writes the arguments of an
+    /// indirect draw. Falls back to [`Cpu`](Self::Cpu) on platforms without compute shaders and
+    /// storage buffers, or while the pipeline is still compiling.
+    Gpu,
+}
+
+#[derive(ExtractResource, Debug, Clone)]
+pub struct InstanceCullingSettings {
+    pub mode: InstanceCullingMode,
+}
+
+impl Default for InstanceCullingSettings {
+    fn default() -> Self {
+        Self {
+            mode: InstanceCullingMode::Gpu,
+        }
+    }
+}
+
+/// Number of material instances and how many of them are inside the frustum of at least one
+/// view, as counted by the last frame the render world prepared
+#[derive(Clone)]
+pub struct InstanceCullingStats {
+    visible: Arc<AtomicUsize>,
+    total: Arc<AtomicUsize>,
+}
+
+impl Default for InstanceCullingStats {
+    fn default() -> Self {
+        Self {
+            visible: Arc::new(AtomicUsize::new(Self::UNKNOWN)),
+            total: default(),
+        }
+    }
+}
+
+impl InstanceCullingStats {
+    pub const VISIBLE_INSTANCES: DiagnosticId =
+        DiagnosticId::from_u128(218944733019744960123887412870931355157);
+    pub const TOTAL_INSTANCES: DiagnosticId =
+        DiagnosticId::from_u128(62347926108418733265290838152874720983);
+
+    const UNKNOWN: usize = usize::MAX;
+
+    /// `None` when some of the instances were culled on the GPU, which keeps its count to itself
+    pub fn visible(&self) -> Option<usize> {
+        Some(self.visible.load(Ordering::Relaxed)).filter(|visible| *visible != Self::UNKNOWN)
+    }
+
+    pub fn total(&self) -> usize {
+        self.total.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set(&self, visible: Option<usize>, total: usize) {
+        self.visible
+            .store(visible.unwrap_or(Self::UNKNOWN), Ordering::Relaxed);
+        self.total.store(total, Ordering::Relaxed);
+    }
+}
+
+type InstanceBoundsQuery<'a> = (
+    Entity,
+    &'a Handle<Mesh>,
+    ChangeTrackers<Handle<Mesh>>,
+    Option<&'a Aabb>,
+);
+
+impl InstanceCullingPlugin {
+    fn setup_diagnostics_system(mut diagnostics: ResMut<Diagnostics>) {
+        diagnostics.add(Diagnostic::new(
+            InstanceCullingStats::VISIBLE_INSTANCES,
+            "visible_instances",
+            20,
+        ));
+        diagnostics.add(Diagnostic::new(
+            InstanceCullingStats::TOTAL_INSTANCES,
+            "total_instances",
+            20,
+        ));
+    }
+
+    fn diagnostics_system(stats: Res<InstanceCullingStats>, mut diagnostics: ResMut<Diagnostics>) {
+        if let Some(visible) = stats.visible() {
+            diagnostics.add_measurement(InstanceCullingStats::VISIBLE_INSTANCES, || visible as f64);
+        }
+        diagnostics.add_measurement(InstanceCullingStats::TOTAL_INSTANCES, || {
+            stats.total() as f64
+        });
+    }
+
+    fn culling_mode_input_system(
+        input: Res<Input<KeyCode>>,
+        controls: Res<CameraControllerSettings>,
+        mut settings: ResMut<InstanceCullingSettings>,
+    ) {
+        if controls.just_pressed(&input, CameraAction::ToggleCullingMode) {
+            settings.mode = match settings.mode {
+                InstanceCullingMode::Cpu => InstanceCullingMode::Gpu,
+                InstanceCullingMode::Gpu => InstanceCullingMode::Cpu,
+            };
+            info!("Culling instances on the {:?}", settings.mode);
+        }
+    }
+
+    /// Bevy only computes the [`Aabb`] of meshes it frustum culls itself, which material instances
+    /// opt out of with `NoFrustumCulling`. It also gets recomputed when the mesh changes, so
+    /// culling and picking never use the bounds of the old one.
+    fn calculate_instance_bounds(
+        mut commands: Commands,
+        mut mesh_events: EventReader<AssetEvent<Mesh>>,
+        meshes: Res<Assets<Mesh>>,
+        query: Query<InstanceBoundsQuery, With<MaterialInstances>>,
+    ) {
+        let modified_meshes: HashSet<_> = mesh_events
+            .iter()
+            .filter_map(|event| match event {
+                AssetEvent::Modified { handle } => Some(handle.id),
+                _ => None,
+            })
+            .collect();
+
+        for (entity, mesh_handle, mesh_tracker, aabb) in &query {
+            let stale = aabb.is_none()
+                || mesh_tracker.is_changed()
+                || modified_meshes.contains(&mesh_handle.id);
+            if !stale {
+                continue;
+            }
+            match meshes.get(mesh_handle).and_then(Mesh::compute_aabb) {
+                Some(aabb) => {
+                    commands.entity(entity).insert(aabb);
+                }
+                // Comes back once the new mesh has loaded
+                None if aabb.is_some() => {
+                    commands.entity(entity).remove::<Aabb>();
+                }
+                None => {}
+            }
+        }
+    }
+}
+
+/// The frustum of a [`CustomCamera`] in the render world
+#[derive(Component, Debug, Clone, Copy)]
+pub struct CullingFrustum {
+    pub frustum: Frustum,
+    /// Cameras with an infinite far plane don't cull anything behind their `far` distance
+    pub intersect_far: bool,
+}
+
+impl CullingFrustum {
+    /// Whether the mesh bounds `aabb` placed by the instance's `model` matrix are in view
+    pub fn intersects_instance(&self, aabb: &Aabb, model: &Mat4) -> bool {
+        self.frustum.intersects_obb(aabb, model, self.intersect_far)
+    }
+}
+
+fn extract_instance_culling(
+    mut commands: Commands,
+    cameras: Extract<Query<(Entity, &Frustum, &CustomCamera)>>,
+    instances: Extract<Query<(Entity, &Aabb), With<CustomMaterial>>>,
+) {
+    for (entity, frustum, camera) in cameras.iter() {
+        commands.get_or_spawn(entity).insert(CullingFrustum {
+            frustum: *frustum,
+            intersect_far: !camera.infinite_far,
+        });
+    }
+    for (entity, aabb) in instances.iter() {
+        commands.get_or_spawn(entity).insert(aabb.clone());
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, ShaderType)]
+#[repr(C)]
+struct InstanceCullingUniform {
+    planes: [Vec4; 6],
+    aabb_center: Vec3,
+    instance_count: u32,
+    aabb_half_extents: Vec3,
+    /// Size of an instance in 32 bit words
+    instance_words: u32,
+    plane_count: u32,
+}
+
+/// The compute pipeline culling the instances on the GPU, and this frame's dispatches of it
+pub struct GpuInstanceCulling {
+    pipeline: CachedComputePipelineId,
+    bind_group_layout: BindGroupLayout,
+    /// Compute shaders and storage buffers are missing on some platforms, i.e. WebGL2
+    supported: bool,
+    dispatches: Vec<(BindGroup, u32)>,
+}
+
+impl FromWorld for GpuInstanceCulling {
+    fn from_world(world: &mut World) -> Self {
+        let shader = world
+            .resource::<AssetServer>()
+            .load("shaders/instance_culling.wgsl");
+        let render_device = world.resource::<RenderDevice>();
+        let supported = render_device.limits().max_storage_buffers_per_shader_stage >= 3;
+
+        let storage_entry = |binding, read_only| BindGroupLayoutEntry {
+            binding,
+            visibility: ShaderStages::COMPUTE,
+            ty: BindingType::Buffer {
+                ty: BufferBindingType::Storage { read_only },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let bind_group_layout =
+            render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                label: Some("Instance culling"),
+                entries: &[
+                    BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: Some(InstanceCullingUniform::min_size()),
+                        },
+                        count: None,
+                    },
+                    // All the instances
+                    storage_entry(1, true),
+                    // The visible ones
+                    storage_entry(2, false),
+                    // The indirect draw arguments
+                    storage_entry(3, false),
+                ],
+            });
+
+        let pipeline = world
+            .resource_mut::<PipelineCache>()
+            .queue_compute_pipeline(ComputePipelineDescriptor {
+                label: Some("instance culling pipeline".into()),
+                layout: Some(vec![bind_group_layout.clone()]),
+                shader,
+                shader_defs: vec![],
+                entry_point: "cull".into(),
+            });
+
+        Self {
+            pipeline,
+            bind_group_layout,
+            supported,
+            dispatches: Vec::new(),
+        }
+    }
+}
+
+impl GpuInstanceCulling {
+    /// Whether the instances can be culled on the GPU this frame
+    pub fn is_available(&self, pipeline_cache: &PipelineCache) -> bool {
+        self.supported && pipeline_cache.get_compute_pipeline(self.pipeline).is_some()
+    }
+
+    /// Usage the instance buffers need for the compute pass to read them
+    pub fn instance_buffer_usage(&self) -> BufferUsages {
+        if self.supported {
+            BufferUsages::VERTEX | BufferUsages::STORAGE
+        } else {
+            BufferUsages::VERTEX
+        }
+    }
+
+    /// Forgets the last frame's dispatches, before the new ones get added
+    pub fn clear(&mut self) {
+        self.dispatches.clear();
+    }
+}
+
+/// The visible instances of a custom material entity from one view, as written by the compute
+/// pass, along with the arguments of the indirect draw drawing them
+pub struct IndirectInstanceBuffer {
+    pub buffer: Buffer,
+    /// `DrawIndexedIndirect` arguments for indexed meshes, `DrawIndirect` ones otherwise
+    pub indirect: Buffer,
+}
+
+/// Buffers culling the instances of a custom material entity for one view on the GPU, kept
+/// across frames
+#[derive(Default)]
+pub struct GpuCulledInstances {
+    uniform: UniformBuffer<InstanceCullingUniform>,
+    visible: Option<(Buffer, u64)>,
+    indirect: Option<Buffer>,
+    bind_group: Option<([BufferId; 3], BindGroup)>,
+}
+
+impl GpuCulledInstances {
+    /// Queues culling `instances`, which hold `instance_size` bytes per instance, against
+    /// `frustum`. The returned buffers are only filled in once the compute pass has run.
+    #[allow(clippy::too_many_arguments)]
+    pub fn prepare(
+        &mut self,
+        culling: &mut GpuInstanceCulling,
+        instances: &InstanceBuffer,
+        instance_size: usize,
+        frustum: &CullingFrustum,
+        aabb: &Aabb,
+        buffer_info: &GpuBufferInfo,
+        render_device: &RenderDevice,
+        render_queue: &RenderQueue,
+    ) -> IndirectInstanceBuffer {
+        let planes = frustum.frustum.planes.map(|plane| plane.normal_d());
+        let uniform = InstanceCullingUniform {
+            planes,
+            aabb_center: aabb.center.into(),
+            instance_count: instances.length as u32,
+            aabb_half_extents: aabb.half_extents.into(),
+            instance_words: (instance_size / 4) as u32,
+            plane_count: if frustum.intersect_far { 6 } else { 5 },
+        };
+        // Only upload the uniform when the view or the instances changed
+        if self.uniform.buffer().is_none() || *self.uniform.get() != uniform {
+            self.uniform.set(uniform);
+            self.uniform.write_buffer(render_device, render_queue);
+        }
+
+        // Only grow the buffer of visible instances, the previous frame's ones fit just as well
+        let size = (instances.length.max(1) * instance_size) as u64;
+        if !matches!(&self.visible, Some((_, capacity)) if *capacity >= size) {
+            let buffer = render_device.create_buffer(&BufferDescriptor {
+                label: Some("visible instance buffer"),
+                size,
+                usage: BufferUsages::VERTEX | BufferUsages::STORAGE,
+                mapped_at_creation: false,
+            });
+            self.visible = Some((buffer, size));
+        }
+        let visible = self
+            .visible
+            .as_ref()
+            .map(|(buffer, _)| buffer.clone())
+            .unwrap();
+        let indirect = self
+            .indirect
+            .get_or_insert_with(|| {
+                render_device.create_buffer(&BufferDescriptor {
+                    label: Some("instance culling indirect buffer"),
+                    size: std::mem::size_of::<[u32; 5]>() as u64,
+                    usage: BufferUsages::INDIRECT | BufferUsages::STORAGE | BufferUsages::COPY_DST,
+                    mapped_at_creation: false,
+                })
+            })
+            .clone();
+
+        // The instance count starts at 0 and the compute pass adds every visible instance to it
+        let args: [u32; 5] = match buffer_info {
+            // index_count, instance_count, first_index, base_vertex, first_instance
+            GpuBufferInfo::Indexed { count, .. } => [*count, 0, 0, 0, 0],
+            // vertex_count, instance_count, first_vertex, first_instance
+            GpuBufferInfo::NonIndexed { vertex_count } => [*vertex_count, 0, 0, 0, 0],
+        };
+        render_queue.write_buffer(&indirect, 0, bytemuck::cast_slice(&args));
+
+        let uniform = self.uniform.buffer().unwrap();
+        let key = [uniform.id(), instances.buffer.id(), visible.id()];
+        let bind_group = cached_bind_group(&mut self.bind_group, key, || {
+            let whole = |buffer| {
+                BindingResource::Buffer(BufferBinding {
+                    buffer,
+                    offset: 0,
+                    size: None,
+                })
+            };
+            render_device.create_bind_group(&BindGroupDescriptor {
+                label: Some("instance culling bind group"),
+                layout: &culling.bind_group_layout,
+                entries: &[
+                    BindGroupEntry {
+                        binding: 0,
+                        resource: whole(uniform),
+                    },
+                    BindGroupEntry {
+                        binding: 1,
+                        resource: whole(&instances.buffer),
+                    },
+                    BindGroupEntry {
+                        binding: 2,
+                        resource: whole(&visible),
+                    },
+                    BindGroupEntry {
+                        binding: 3,
+                        resource: whole(&indirect),
+                    },
+                ],
+            })
+        });
+        let workgroups = (instances.length as u32 + INSTANCE_CULLING_WORKGROUP_SIZE - 1)
+            / INSTANCE_CULLING_WORKGROUP_SIZE;
+        culling.dispatches.push((bind_group, workgroups));
+
+        IndirectInstanceBuffer {
+            buffer: visible,
+            indirect,
+        }
+    }
+}
+
+/// Culls the instances on the GPU before the cameras get drawn
+pub struct InstanceCullingNode;
+
+impl Node for InstanceCullingNode {
+    fn run(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        let culling = world.resource::<GpuInstanceCulling>();
+        let pipeline = match world
+            .resource::<PipelineCache>()
+            .get_compute_pipeline(culling.pipeline)
+        {
+            Some(pipeline) => pipeline,
+            None => return Ok(()),
+        };
+        if culling.dispatches.is_empty() {
+            return Ok(());
+        }
+
+        let mut pass = render_context
+            .command_encoder
+            .begin_compute_pass(&ComputePassDescriptor {
+                label: Some("instance_culling_pass"),
+            });
+        pass.set_pipeline(pipeline);
+        for (bind_group, workgroups) in &culling.dispatches {
+            pass.set_bind_group(0, bind_group, &[]);
+            pass.dispatch_workgroups(*workgroups, 1, 1);
+        }
+
+        Ok(())
+    }
+}
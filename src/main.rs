@@ -1,25 +1,38 @@
 mod camera;
+mod camera_path;
+mod camera_settings;
+mod child_instances;
 mod custom_material;
+mod instance_culling;
+mod picking;
 mod point_light_material;
+mod shadow;
+mod view_bind_group;
 
 use camera::*;
+use camera_path::*;
+use camera_settings::*;
+use child_instances::*;
 use custom_material::*;
+use instance_culling::*;
+use picking::*;
 use point_light_material::*;
+use shadow::*;
+use view_bind_group::*;
 
 use bevy::{
     asset::LoadState,
     core_pipeline::clear_color::ClearColorConfig,
+    diagnostic::LogDiagnosticsPlugin,
     prelude::*,
     render::{
         extract_component::{ExtractComponent, ExtractComponentPlugin},
-        extract_resource::{ExtractResource, ExtractResourcePlugin},
         render_resource::{
             AddressMode, Extent3d, FilterMode, PrimitiveTopology, SamplerDescriptor,
             TextureDescriptor, TextureDimension, TextureFormat, TextureUsages,
         },
         texture::ImageSampler,
         view::NoFrustumCulling,
-        MainWorld, RenderApp, RenderStage,
     },
     window::close_on_esc,
 };
@@ -27,6 +40,14 @@ use bevy::{
 #[derive(Deref, DerefMut, Debug)]
 pub struct TextureShaderResources(Option<Vec<Handle<Image>>>);
 
+/// Marks the point light that gets moved around by `move_light`
+#[derive(Component)]
+struct MovingLight;
+
+/// Marks the child entity instances that get spun around by `spin_instances`
+#[derive(Component)]
+struct SpinningInstance;
+
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
 enum AppState {
     LoadAssets,
@@ -69,25 +90,28 @@ impl ExtractComponent for EmissionTexture {
     }
 }
 
-#[derive(ExtractResource, Clone, Debug)]
+/// A light shining along the forward direction of the entity's [`Transform`]
+#[derive(Component, Clone, Debug)]
 pub struct DirectionalLight {
-    pub direction: Vec3,
     pub ambient: Vec4,
     pub diffuse: Vec4,
     pub specular: Vec4,
+    pub shadow: Option<DirectionalLightShadow>,
 }
 impl Default for DirectionalLight {
     fn default() -> Self {
         Self {
-            direction: Vec3::splat(0.0),
             ambient: Vec4::splat(0.0),
             diffuse: Vec4::splat(0.0),
             specular: Vec4::splat(0.0),
+            shadow: None,
         }
     }
 }
 
-#[derive(ExtractResource, Clone, Debug)]
+/// A spotlight at the entity's [`Transform`], shining along its forward direction.
+/// Add a [`Flashlight`] to keep it attached to the camera instead.
+#[derive(Component, Clone, Debug)]
 pub struct Spotlight {
     pub cutoff: f32,
     pub outer_cutoff: f32,
@@ -97,6 +121,8 @@ pub struct Spotlight {
     pub constant: f32,
     pub linear: f32,
     pub quadratic: f32,
+    /// Render a shadow map for this light, see [`SpotlightShadowSettings`]
+    pub casts_shadows: bool,
 }
 impl Default for Spotlight {
     fn default() -> Self {
@@ -109,6 +135,7 @@ impl Default for Spotlight {
             constant: 1.0,
             linear: 1.0,
             quadratic: 1.0,
+            casts_shadows: false,
         }
     }
 }
@@ -278,28 +305,35 @@ fn main() {
     //},
     //})
     .insert_resource(TextureShaderResources(None))
-    .init_resource::<DirectionalLight>()
-    .init_resource::<Spotlight>()
     .add_plugins(DefaultPlugins)
     .add_plugin(ExtractComponentPlugin::<DiffuseTexture>::default())
     .add_plugin(ExtractComponentPlugin::<SpecularTexture>::default())
     .add_plugin(ExtractComponentPlugin::<EmissionTexture>::default())
-    .add_plugin(ExtractResourcePlugin::<DirectionalLight>::default())
-    .add_plugin(ExtractResourcePlugin::<Spotlight>::default())
+    // The material pipelines use the custom view bind group layout, so it has to come first
+    .add_plugin(CustomViewBindGroupPlugin)
     .add_plugin(PointLightMaterialPlugin)
+    .add_plugin(ShadowPlugin)
     .add_plugin(CustomMaterialPlugin)
+    .add_plugin(ChildInstancesPlugin)
+    .add_plugin(InstanceCullingPlugin)
+    .add_plugin(LogDiagnosticsPlugin::filtered(vec![
+        InstanceCullingStats::VISIBLE_INSTANCES,
+        InstanceCullingStats::TOTAL_INSTANCES,
+    ]))
     .add_plugin(CameraPlugin)
+    .add_plugin(CameraPathPlugin)
+    .add_plugin(PickingPlugin)
     .add_state(AppState::LoadAssets)
     .add_system_set(SystemSet::on_enter(AppState::LoadAssets).with_system(load_assets))
     .add_system_set(SystemSet::on_update(AppState::LoadAssets).with_system(assets_loaded))
     .add_system_set(SystemSet::on_enter(AppState::Main).with_system(setup))
-    .add_system_set(SystemSet::on_update(AppState::Main).with_system(move_light))
+    .add_system_set(
+        SystemSet::on_update(AppState::Main)
+            .with_system(move_light)
+            .with_system(spin_instances),
+    )
     .add_system(close_on_esc);
 
-    app.sub_app_mut(RenderApp)
-        .init_resource::<CustomCamera>()
-        .add_system_to_stage(RenderStage::Extract, extract_custom_camera);
-
     app.run();
 }
 
@@ -332,19 +366,13 @@ fn assets_loaded(
     }
 }
 
-fn extract_custom_camera(mut commands: Commands, world: Res<MainWorld>) {
-    if let Some(camera) = world.get_resource::<CustomCamera>() {
-        commands.insert_resource(camera.clone());
-    }
-}
-
 fn move_light(
-    mut query: Query<&mut PointLightInstances, With<PointLightMaterial>>,
+    mut query: Query<&mut Transform, (With<PointLightInstance>, With<MovingLight>)>,
     time: Res<Time>,
 ) {
-    for mut light_instances in &mut query {
+    for mut transform in &mut query {
         let time_val = time.seconds_since_startup() as f32;
-        light_instances[0].position.x = time_val.sin();
+        transform.translation.x = time_val.sin();
 
         //let light_col = Vec4::new(
         //(time_val * 2.0).sin(),
@@ -354,8 +382,14 @@ fn move_light(
         //);
         //let diffuse_col = light_col * Vec4::splat(0.5);
         //let ambient_col = diffuse_col * Vec4::splat(0.2);
-        //light_instances[0].diffuse = diffuse_col;
-        //light_instances[0].ambient = ambient_col;
+        //light_instance.diffuse = diffuse_col;
+        //light_instance.ambient = ambient_col;
+    }
+}
+
+fn spin_instances(mut query: Query<&mut Transform, With<SpinningInstance>>, time: Res<Time>) {
+    for mut transform in &mut query {
+        transform.rotate_y(time.delta_seconds());
     }
 }
 
@@ -370,32 +404,25 @@ fn setup(
     window.set_cursor_lock_mode(true);
     window.set_cursor_visibility(false);
 
-    // This is just used so that we can see something through bevy, there's another camera created
-    // using model, view, projection matrices
-    commands.spawn_bundle(Camera3dBundle {
+    commands.spawn_bundle(CustomCameraBundle {
+        custom_camera: CustomCamera {
+            position: Vec3::new(0.0, 0.0, 3.0),
+            yaw: (-90.0_f32).to_radians(),
+            pitch: 0.0_f32.to_radians(),
+            up: Vec3::Y,
+            fov: 45.0,
+            aspect_ratio: 800.0 / 600.0,
+            near: 0.1,
+            far: 100.0,
+            ..default()
+        },
         camera_3d: Camera3d {
-            // This is 0.0 by default because 0.0 is the far plane due to bevy's use of reverse-z projections.
-            // This goes hand in hand with the DepthStencilState depth_compare
-            // If it's Less the load op needs to be 1.0
-            // If it's Greater the load op needs to be 0.0
-            // TODO: figure out why???
-            depth_load_op: bevy::core_pipeline::core_3d::Camera3dDepthLoadOp::Clear(1.0),
             clear_color: ClearColorConfig::Custom(Color::BLACK),
+            ..default()
         },
         ..default()
     });
 
-    commands.insert_resource(CustomCamera {
-        position: Vec3::new(0.0, 0.0, 3.0),
-        yaw: (-90.0_f32).to_radians(),
-        pitch: 0.0_f32.to_radians(),
-        up: Vec3::Y,
-        fov: 45.0,
-        aspect_ratio: 800.0 / 600.0,
-        near: 0.1,
-        far: 100.0,
-    });
-
     let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
     mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, CUBE.to_vec());
     mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, CUBE_NORMALS.to_vec());
@@ -407,68 +434,96 @@ fn setup(
     //v_color,
     //);
 
-    commands.insert_resource(DirectionalLight {
-        direction: Vec3::new(-0.2, -1.0, -0.3),
-        ambient: Vec3::splat(0.05).extend(1.0),
-        diffuse: Vec3::splat(0.4).extend(1.0),
-        specular: Vec3::splat(0.5).extend(1.0),
-    });
+    commands
+        .spawn_bundle(TransformBundle::from_transform(
+            // Centered on the cubes so they all fit in the shadow map
+            Transform::from_xyz(0.0, 0.0, -6.0).looking_at(
+                Vec3::new(0.0, 0.0, -6.0) + Vec3::new(-0.2, -1.0, -0.3),
+                Vec3::Y,
+            ),
+        ))
+        .insert(DirectionalLight {
+            ambient: Vec3::splat(0.05).extend(1.0),
+            diffuse: Vec3::splat(0.4).extend(1.0),
+            specular: Vec3::splat(0.5).extend(1.0),
+            shadow: Some(DirectionalLightShadow::default()),
+        });
 
-    commands.insert_resource(Spotlight {
-        cutoff: 12.5,
-        outer_cutoff: 15.0,
-        ambient: Vec3::splat(0.1).extend(1.0),
-        diffuse: Vec3::splat(1.0).extend(1.0),
-        specular: Vec3::splat(1.0).extend(1.0),
-        constant: 1.0,
-        linear: 0.09,
-        quadratic: 0.032,
-    });
+    commands
+        .spawn_bundle(TransformBundle::default())
+        .insert(Flashlight)
+        .insert(Spotlight {
+            cutoff: 12.5,
+            outer_cutoff: 15.0,
+            ambient: Vec3::splat(0.1).extend(1.0),
+            diffuse: Vec3::splat(1.0).extend(1.0),
+            specular: Vec3::splat(1.0).extend(1.0),
+            constant: 1.0,
+            linear: 0.09,
+            quadratic: 0.032,
+            casts_shadows: true,
+        });
 
     commands
-        .spawn()
+        .spawn_bundle(TransformBundle::from_transform(Transform::from_xyz(
+            0.7, 0.2, 2.0,
+        )))
         .insert_bundle((
-            meshes.add(mesh),
-            PointLightInstances(vec![
-                PointLightInstance {
-                    position: Vec3::new(0.7, 0.2, 2.0),
-                    constant: 1.0,
-                    linear: 0.09,
-                    quadratic: 0.032,
-                    ambient: Vec3::splat(0.05).extend(1.0),
-                    diffuse: Vec3::splat(0.8).extend(1.0),
-                    specular: Vec3::splat(1.0).extend(1.0),
-                },
-                PointLightInstance {
-                    position: Vec3::new(2.3, -3.3, -4.0),
-                    constant: 1.0,
-                    linear: 0.09,
-                    quadratic: 0.032,
-                    ambient: Vec4::from(Color::RED),
-                    diffuse: Vec4::from(Color::RED),
-                    specular: Vec3::splat(1.0).extend(1.0),
-                },
-                PointLightInstance {
-                    position: Vec3::new(-4.0, 2.0, -1.0),
-                    constant: 1.0,
-                    linear: 0.09,
-                    quadratic: 0.032,
-                    ambient: Vec3::splat(0.05).extend(1.0),
-                    diffuse: Vec3::splat(0.8).extend(1.0),
-                    specular: Vec3::splat(1.0).extend(1.0),
-                },
-                PointLightInstance {
-                    position: Vec3::new(0.0, 0.0, -3.0),
-                    constant: 1.0,
-                    linear: 0.09,
-                    quadratic: 0.032,
-                    ambient: Vec3::splat(0.05).extend(1.0),
-                    diffuse: Vec3::splat(0.8).extend(1.0),
-                    specular: Vec3::splat(1.0).extend(1.0),
-                },
-            ]),
-            PointLightMaterial,
-        ))
+            PointLightInstance {
+                constant: 1.0,
+                linear: 0.09,
+                quadratic: 0.032,
+                ambient: Vec3::splat(0.05).extend(1.0),
+                diffuse: Vec3::splat(0.8).extend(1.0),
+                specular: Vec3::splat(1.0).extend(1.0),
+                casts_shadows: true,
+            },
+            MovingLight,
+        ));
+    commands
+        .spawn_bundle(TransformBundle::from_transform(Transform::from_xyz(
+            2.3, -3.3, -4.0,
+        )))
+        .insert(PointLightInstance {
+            constant: 1.0,
+            linear: 0.09,
+            quadratic: 0.032,
+            ambient: Vec4::from(Color::RED),
+            diffuse: Vec4::from(Color::RED),
+            specular: Vec3::splat(1.0).extend(1.0),
+            casts_shadows: true,
+        });
+    commands
+        .spawn_bundle(TransformBundle::from_transform(Transform::from_xyz(
+            -4.0, 2.0, -1.0,
+        )))
+        .insert(PointLightInstance {
+            constant: 1.0,
+            linear: 0.09,
+            quadratic: 0.032,
+            ambient: Vec3::splat(0.05).extend(1.0),
+            diffuse: Vec3::splat(0.8).extend(1.0),
+            specular: Vec3::splat(1.0).extend(1.0),
+            casts_shadows: false,
+        });
+    commands
+        .spawn_bundle(TransformBundle::from_transform(Transform::from_xyz(
+            0.0, 0.0, -3.0,
+        )))
+        .insert(PointLightInstance {
+            constant: 1.0,
+            linear: 0.09,
+            quadratic: 0.032,
+            ambient: Vec3::splat(0.05).extend(1.0),
+            diffuse: Vec3::splat(0.8).extend(1.0),
+            specular: Vec3::splat(1.0).extend(1.0),
+            casts_shadows: false,
+        });
+
+    // Draws a cube for every point light
+    commands
+        .spawn()
+        .insert_bundle((meshes.add(mesh), PointLightMaterial))
         .insert_bundle(SpatialBundle::default());
 
     match &**textures {
@@ -526,7 +581,7 @@ fn setup(
             });
 
             /////////////////////////// Emission texture
-            let mut emission = images.get_mut(&textures[1]).unwrap();
+            let mut emission = images.get_mut(&textures[2]).unwrap();
             emission.texture_descriptor = TextureDescriptor {
                 label: None,
                 size: Extent3d {
@@ -560,32 +615,98 @@ fn setup(
             //let indices = vec![0, 1, 3, 1, 2, 3];
             //mesh.set_indices(Some(Indices::U16(indices)));
 
+            let mesh = meshes.add(mesh);
             commands
                 .spawn()
                 .insert_bundle((
-                    meshes.add(mesh),
+                    mesh.clone(),
                     MaterialInstances(
                         (0..10)
-                            .map(|i| MaterialInstance {
-                                position: CUBE_POS[i],
-                                rotation_y: (20.0_f32 * i as f32).to_radians(),
-                                rotation_x: (10.0_f32 * i as f32).to_radians(),
-                                rotation_z: 0.0,
-                                shininess: 25.0,
+                            .map(|i| {
+                                MaterialInstance::from_euler(
+                                    CUBE_POS[i],
+                                    (10.0_f32 * i as f32).to_radians(),
+                                    (20.0_f32 * i as f32).to_radians(),
+                                    0.0,
+                                    25.0,
+                                )
                             })
                             .collect(),
                     ),
                     DiffuseTexture(textures[0].clone()),
                     SpecularTexture(textures[1].clone()),
                     EmissionTexture(textures[2].clone()),
+                    EmissionSettings {
+                        mask_by_specular: true,
+                        scroll: Vec2::new(0.0, 0.1),
+                        ..default()
+                    },
+                    CustomMaterial,
+                    // NOTE: The built-in frustum culling would test the Aabb of the Mesh against the
+                    // GlobalTransform of the entity, which knows nothing about where the instances
+                    // are, so it gets disabled with the `NoFrustumCulling` marker component. Each
+                    // instance is culled on its own in `prepare_buffers` instead.
+                    NoFrustumCulling,
+                ))
+                .insert_bundle(SpatialBundle::default());
+
+            // A few more cubes, each one its own entity so it can be moved on its own
+            commands
+                .spawn()
+                .insert_bundle((
+                    mesh.clone(),
+                    ChildInstances,
+                    DiffuseTexture(textures[0].clone()),
+                    SpecularTexture(textures[1].clone()),
+                    EmissionTexture(textures[2].clone()),
+                    CustomMaterial,
+                    NoFrustumCulling,
+                ))
+                .insert_bundle(SpatialBundle::from_transform(Transform::from_xyz(
+                    0.0, -2.0, -4.0,
+                )))
+                .with_children(|parent| {
+                    // Same textures, but each one tinted differently
+                    let tints = [
+                        (-2.0, Color::rgb(1.0, 0.4, 0.4)),
+                        (0.0, Color::rgb(0.4, 1.0, 0.4)),
+                        (2.0, Color::rgb(0.4, 0.4, 1.0)),
+                    ];
+                    for (x, tint) in tints {
+                        parent
+                            .spawn_bundle(SpatialBundle::from_transform(
+                                Transform::from_xyz(x, 0.0, 0.0)
+                                    .with_scale(Vec3::new(0.5, 1.0, 0.5)),
+                            ))
+                            .insert_bundle((
+                                InstanceMaterial {
+                                    shininess: 64.0,
+                                    diffuse_tint: tint,
+                                    uv_scale: Vec2::splat(2.0),
+                                    ..default()
+                                },
+                                SpinningInstance,
+                            ));
+                    }
+                });
+
+            // A floor without any textures, colored by its material colors alone
+            commands
+                .spawn()
+                .insert_bundle((
+                    mesh,
+                    MaterialInstances(vec![MaterialInstance {
+                        transform: Transform::from_xyz(0.0, -3.0, -2.0)
+                            .with_scale(Vec3::new(12.0, 0.1, 12.0)),
+                        shininess: 8.0,
+                        ..default()
+                    }]),
+                    MaterialColors {
+                        diffuse: Color::rgb(0.35, 0.35, 0.4),
+                        specular: Color::rgb(0.2, 0.2, 0.2),
+                        ..default()
+                    },
                     CustomMaterial,
-                    // NOTE: Frustum culling is done based on the Aabb of the Mesh and the GlobalTransform.
-                    // As the cube is at the origin, if its Aabb moves outside the view frustum, all the
-                    // instanced cubes will be culled.
-                    // The InstanceMaterialData contains the 'GlobalTransform' information for this custom
-                    // instancing, and that is not taken into account with the built-in frustum culling.
-                    // We must disable the built-in frustum culling by adding the `NoFrustumCulling` marker
-                    // component to avoid incorrect culling.
                     NoFrustumCulling,
                 ))
                 .insert_bundle(SpatialBundle::default());
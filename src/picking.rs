@@ -0,0 +1,201 @@
+use crate::{
+    AppState, CustomCamera, CustomMaterial, HighlightedInstance, InstanceEntities,
+    MaterialInstances,
+};
+use bevy::{
+    prelude::*,
+    render::{camera::RenderTarget, primitives::Aabb},
+};
+
+/// Casts a ray from the cursor into the [`MaterialInstances`], highlighting the one it hits first
+/// and sending a [`PickEvent`] when it gets clicked
+pub struct PickingPlugin;
+
+impl Plugin for PickingPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<HoveredInstance>()
+            .add_event::<PickEvent>()
+            .add_system_set(
+                SystemSet::on_update(AppState::Main)
+                    .with_system(Self::hover_system)
+                    .with_system(Self::pick_system.after(Self::hover_system)),
+            );
+    }
+}
+
+type PickableQuery<'a> = (
+    Entity,
+    &'a MaterialInstances,
+    Option<&'a Aabb>,
+    Option<&'a HighlightedInstance>,
+    Option<&'a InstanceEntities>,
+);
+
+/// One of the instances of a [`MaterialInstances`] entity
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PickedInstance {
+    pub entity: Entity,
+    /// Index into the [`MaterialInstances`]
+    pub instance: usize,
+    /// The child entity of the instance, when they are [`ChildInstances`](crate::ChildInstances)
+    pub instance_entity: Option<Entity>,
+}
+
+/// Sent when an instance gets clicked
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deref)]
+pub struct PickEvent(pub PickedInstance);
+
+/// The instance under the cursor, or under the center of the screen while the cursor is locked
+#[derive(Debug, Default, Deref)]
+pub struct HoveredInstance(pub Option<PickedInstance>);
+
+impl PickingPlugin {
+    fn hover_system(
+        mut commands: Commands,
+        windows: Res<Windows>,
+        cameras: Query<(&Camera, &CustomCamera)>,
+        instances: Query<PickableQuery, With<CustomMaterial>>,
+        mut hovered: ResMut<HoveredInstance>,
+    ) {
+        let ray = cameras
+            .iter()
+            .next()
+            .and_then(|(camera, custom_camera)| cursor_ray(&windows, camera, custom_camera));
+
+        let mut closest: Option<(f32, PickedInstance)> = None;
+        if let Some((origin, direction)) = ray {
+            // The same bounds the instances get culled with, which are missing until their mesh
+            // has loaded
+            for (entity, instances, aabb, _, instance_entities) in &instances {
+                let aabb = match aabb {
+                    Some(aabb) => aabb,
+                    None => continue,
+                };
+                for (instance, material_instance) in instances.iter().enumerate() {
+                    let distance = match ray_box_intersection(
+                        origin,
+                        direction,
+                        &material_instance.transform,
+                        aabb,
+                    ) {
+                        Some(distance) => distance,
+                        None => continue,
+                    };
+                    if closest.map_or(true, |(closest, _)| distance < closest) {
+                        let instance_entity = instance_entities
+                            .and_then(|instance_entities| instance_entities.get(instance).copied());
+                        closest = Some((
+                            distance,
+                            PickedInstance {
+                                entity,
+                                instance,
+                                instance_entity,
+                            },
+                        ));
+                    }
+                }
+            }
+        }
+
+        let picked = closest.map(|(_, picked)| picked);
+        if hovered.0 != picked {
+            hovered.0 = picked;
+        }
+
+        // Only touch the components when the highlight moves, so the instance buffers don't get
+        // rewritten every frame
+        for (entity, _, _, highlighted, _) in &instances {
+            let highlight = picked
+                .filter(|picked| picked.entity == entity)
+                .map(|picked| HighlightedInstance(picked.instance));
+            match (highlighted, highlight) {
+                (Some(old), Some(new)) if *old == new => {}
+                (_, Some(new)) => {
+                    commands.entity(entity).insert(new);
+                }
+                (Some(_), None) => {
+                    commands.entity(entity).remove::<HighlightedInstance>();
+                }
+                (None, None) => {}
+            }
+        }
+    }
+
+    fn pick_system(
+        hovered: Res<HoveredInstance>,
+        mouse_buttons: Res<Input<MouseButton>>,
+        mut pick_events: EventWriter<PickEvent>,
+    ) {
+        if let Some(picked) = **hovered {
+            if mouse_buttons.just_pressed(MouseButton::Left) {
+                pick_events.send(PickEvent(picked));
+            }
+        }
+    }
+}
+
+/// World space ray from the camera through the cursor, or through the center of the viewport
+/// while the cursor is locked
+fn cursor_ray(
+    windows: &Windows,
+    camera: &Camera,
+    custom_camera: &CustomCamera,
+) -> Option<(Vec3, Vec3)> {
+    let window = match &camera.target {
+        RenderTarget::Window(id) => windows.get(*id)?,
+        RenderTarget::Image(_) => return None,
+    };
+    let window_size = Vec2::new(
+        window.physical_width() as f32,
+        window.physical_height() as f32,
+    );
+    let (viewport_position, viewport_size) = match &camera.viewport {
+        Some(viewport) => (
+            viewport.physical_position.as_vec2(),
+            viewport.physical_size.as_vec2(),
+        ),
+        None => (Vec2::ZERO, window_size),
+    };
+
+    let ndc = if window.cursor_locked() {
+        Vec2::ZERO
+    } else {
+        // The cursor starts at the bottom left of the window, the viewport at the top left
+        let cursor = window.physical_cursor_position()?.as_vec2();
+        let cursor = Vec2::new(cursor.x, window_size.y - cursor.y);
+        let uv = (cursor - viewport_position) / viewport_size;
+        if uv.cmplt(Vec2::ZERO).any() || uv.cmpgt(Vec2::ONE).any() {
+            return None;
+        }
+        Vec2::new(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0)
+    };
+
+    Some(custom_camera.ray_from_ndc(ndc))
+}
+
+/// Distance along the ray to where it first enters `aabb` placed by `transform`, if it does
+fn ray_box_intersection(
+    origin: Vec3,
+    direction: Vec3,
+    transform: &Transform,
+    aabb: &Aabb,
+) -> Option<f32> {
+    // Intersect in the box's own space, where it's axis aligned. The direction gets transformed
+    // along with the origin, so the distance along the ray stays the same. Undoing the
+    // translation, rotation and scale one by one is cheaper than inverting the model matrix.
+    let inverse_rotation = transform.rotation.inverse();
+    let origin = inverse_rotation * (origin - transform.translation) / transform.scale
+        - Vec3::from(aabb.center);
+    let direction = inverse_rotation * direction / transform.scale;
+    let half_extents = Vec3::from(aabb.half_extents);
+
+    // Slab test, dividing by 0.0 gives infinities that still compare correctly
+    let t1 = (-half_extents - origin) / direction;
+    let t2 = (half_extents - origin) / direction;
+    let t_near = t1.min(t2).max_element();
+    let t_far = t1.max(t2).min_element();
+    if t_near > t_far || t_far < 0.0 {
+        return None;
+    }
+    Some(t_near.max(0.0))
+}
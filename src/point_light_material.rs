@@ -1,4 +1,4 @@
-use crate::{CustomCamera, InstanceBuffer, UniformMeta};
+use crate::{ExtractedLights, InstanceBuffer, PersistentInstanceBuffer};
 use bevy::{
     core_pipeline::core_3d::Transparent3d,
     ecs::system::{
@@ -16,30 +16,30 @@ use bevy::{
             SetItemPipeline, TrackedRenderPass,
         },
         render_resource::{
-            BindGroupDescriptor, BindGroupEntry, BindGroupLayout, BindGroupLayoutDescriptor,
-            BindGroupLayoutEntry, BindingType, BufferBindingType, BufferInitDescriptor, BufferSize,
-            BufferUsages, CompareFunction, DepthBiasState, DepthStencilState, FrontFace,
-            PipelineCache, PolygonMode, PrimitiveState, RenderPipelineDescriptor, ShaderStages,
-            SpecializedMeshPipeline, SpecializedMeshPipelineError, SpecializedMeshPipelines,
-            StencilState, TextureFormat, VertexAttribute, VertexBufferLayout, VertexFormat,
-            VertexStepMode,
+            CompareFunction, DepthBiasState, DepthStencilState, FrontFace, PipelineCache,
+            PolygonMode, PrimitiveState, RenderPipelineDescriptor, SpecializedMeshPipeline,
+            SpecializedMeshPipelineError, SpecializedMeshPipelines, StencilState, TextureFormat,
+            VertexAttribute, VertexBufferLayout, VertexFormat, VertexStepMode,
         },
-        renderer::RenderDevice,
+        renderer::{RenderDevice, RenderQueue},
         view::ExtractedView,
         RenderApp, RenderStage,
     },
+    utils::HashMap,
 };
 use bytemuck::{Pod, Zeroable};
 
+/// A point light positioned by the entity's [`Transform`]
 #[derive(Component, Debug, Clone, Copy)]
 pub struct PointLightInstance {
-    pub position: Vec3,
     pub constant: f32,
     pub linear: f32,
     pub quadratic: f32,
     pub ambient: Vec4,
     pub diffuse: Vec4,
     pub specular: Vec4,
+    /// Render a cube shadow map for this light, see [`PointLightShadowSettings`](crate::PointLightShadowSettings)
+    pub casts_shadows: bool,
 }
 
 #[derive(Component, Debug, Clone, Copy, Pod, Zeroable)]
@@ -51,18 +51,6 @@ struct RenderPointLightInstance {
     specular: Vec4,
 }
 
-#[derive(Component, Deref, DerefMut, Debug)]
-pub struct PointLightInstances(pub Vec<PointLightInstance>);
-
-impl ExtractComponent for PointLightInstances {
-    type Query = &'static PointLightInstances;
-    type Filter = ();
-
-    fn extract_component(item: bevy::ecs::query::QueryItem<Self::Query>) -> Self {
-        PointLightInstances(item.0.clone())
-    }
-}
-
 #[derive(Component)]
 pub struct PointLightMaterial;
 
@@ -79,12 +67,12 @@ pub struct PointLightMaterialPlugin;
 
 impl Plugin for PointLightMaterialPlugin {
     fn build(&self, app: &mut App) {
-        app.add_plugin(ExtractComponentPlugin::<PointLightMaterial>::default())
-            .add_plugin(ExtractComponentPlugin::<PointLightInstances>::default());
+        app.add_plugin(ExtractComponentPlugin::<PointLightMaterial>::default());
         app.sub_app_mut(RenderApp)
             .add_render_command::<Transparent3d, DrawLightMaterial>()
             .init_resource::<PointLightMaterialPipeline>()
             .init_resource::<SpecializedMeshPipelines<PointLightMaterialPipeline>>()
+            .init_resource::<PointLightMaterialBuffers>()
             .add_system_to_stage(RenderStage::Queue, queue_point_light_material)
             .add_system_to_stage(RenderStage::Prepare, prepare_point_light_material_buffers);
     }
@@ -133,109 +121,63 @@ fn queue_point_light_material(
     }
 }
 
+/// Instance buffers of the point light material that live across frames
+#[derive(Default)]
+pub struct PointLightMaterialBuffers {
+    entities: HashMap<Entity, PersistentInstanceBuffer<RenderPointLightInstance>>,
+}
+
 pub fn prepare_point_light_material_buffers(
     mut commands: Commands,
-    query: Query<(Entity, &PointLightInstances), With<PointLightMaterial>>,
-    camera: Res<CustomCamera>,
+    query: Query<Entity, With<PointLightMaterial>>,
+    lights: Res<ExtractedLights>,
     render_device: Res<RenderDevice>,
-    pipeline: Res<PointLightMaterialPipeline>,
+    render_queue: Res<RenderQueue>,
+    mut buffers: ResMut<PointLightMaterialBuffers>,
 ) {
-    for (entity, light_instances) in &query {
-        let instance_buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
-            label: Some("instance data buffer"),
-            contents: bytemuck::cast_slice(
-                light_instances
-                    .iter()
-                    .map(|instance| RenderPointLightInstance {
-                        position: Mat4::from_translation(instance.position),
-                        ambient: instance.ambient,
-                        diffuse: instance.diffuse,
-                        specular: instance.specular,
-                    })
-                    .collect::<Vec<RenderPointLightInstance>>()
-                    .as_slice(),
-            ),
-            usage: BufferUsages::VERTEX | BufferUsages::COPY_DST,
-        });
-        commands.entity(entity).insert(InstanceBuffer {
-            buffer: instance_buffer,
-            length: light_instances.len(),
-        });
+    buffers.entities.retain(|entity, _| query.contains(*entity));
 
-        let view_buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
-            label: Some("view mat buffer"),
-            usage: BufferUsages::COPY_DST | BufferUsages::UNIFORM,
-            contents: bytemuck::cast_slice(&[camera.get_view()]),
-        });
-        let proj_buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
-            label: Some("proj mat buffer"),
-            contents: bytemuck::cast_slice(&[camera.get_proj()]),
-            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
-        });
-        let bind_group = render_device.create_bind_group(&BindGroupDescriptor {
-            label: Some("light uniform bind group"),
-            layout: &pipeline.bind_group_layout,
-            entries: &[
-                BindGroupEntry {
-                    binding: 0,
-                    resource: view_buffer.as_entire_binding(),
-                },
-                BindGroupEntry {
-                    binding: 1,
-                    resource: proj_buffer.as_entire_binding(),
-                },
-            ],
-        });
-        commands.entity(entity).insert(UniformMeta { bind_group });
+    if lights.point_lights.is_empty() {
+        return;
+    }
+
+    for entity in &query {
+        let instance_data = lights
+            .point_lights
+            .iter()
+            .map(|(instance, transform)| RenderPointLightInstance {
+                position: Mat4::from_translation(transform.translation()),
+                ambient: instance.ambient,
+                diffuse: instance.diffuse,
+                specular: instance.specular,
+            })
+            .collect::<Vec<RenderPointLightInstance>>();
+        if let Some(instance_buffer) = buffers.entities.entry(entity).or_default().write(
+            instance_data,
+            &render_device,
+            &render_queue,
+        ) {
+            commands.entity(entity).insert(instance_buffer);
+        }
     }
 }
 
 pub struct PointLightMaterialPipeline {
     shader: Handle<Shader>,
     mesh_pipeline: MeshPipeline,
-    bind_group_layout: BindGroupLayout,
 }
 
 impl FromWorld for PointLightMaterialPipeline {
     fn from_world(world: &mut World) -> Self {
         let asset_server = world.resource::<AssetServer>();
-        let render_device = world.resource::<RenderDevice>();
         asset_server.watch_for_changes().unwrap();
         let shader = asset_server.load("shaders/point_light_mesh.wgsl");
 
         let mesh_pipeline = world.resource::<MeshPipeline>();
 
-        let bind_group_layout =
-            render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
-                label: Some("Uniforms"),
-                entries: &[
-                    BindGroupLayoutEntry {
-                        binding: 0,
-                        visibility: ShaderStages::VERTEX,
-                        ty: BindingType::Buffer {
-                            ty: BufferBindingType::Uniform,
-                            has_dynamic_offset: false,
-                            min_binding_size: BufferSize::new(std::mem::size_of::<Mat4>() as u64),
-                        },
-                        count: None,
-                    },
-                    BindGroupLayoutEntry {
-                        binding: 1,
-                        visibility: ShaderStages::VERTEX,
-                        ty: BindingType::Buffer {
-                            ty: BufferBindingType::Uniform,
-                            has_dynamic_offset: false,
-                            min_binding_size: BufferSize::new(std::mem::size_of::<Mat4>() as u64),
-                        },
-                        count: None,
-                    },
-                ],
-            });
-
         PointLightMaterialPipeline {
             shader,
             mesh_pipeline: mesh_pipeline.clone(),
-            bind_group_layout,
         }
     }
 }
@@ -296,7 +238,6 @@ impl SpecializedMeshPipeline for PointLightMaterialPipeline {
         descriptor.layout = Some(vec![
             self.mesh_pipeline.view_layout.clone(),
             self.mesh_pipeline.mesh_layout.clone(),
-            self.bind_group_layout.clone(),
         ]);
         descriptor.label = Some("Custom Mesh pipeline descriptor".into());
         descriptor.primitive = PrimitiveState {
@@ -311,7 +252,7 @@ impl SpecializedMeshPipeline for PointLightMaterialPipeline {
         descriptor.depth_stencil = Some(DepthStencilState {
             format: TextureFormat::Depth32Float,
             depth_write_enabled: true,
-            depth_compare: CompareFunction::Less,
+            depth_compare: CompareFunction::Greater,
             stencil: StencilState::default(),
             bias: DepthBiasState::default(),
         });
@@ -324,7 +265,6 @@ type DrawLightMaterial = (
     SetItemPipeline,
     SetMeshViewBindGroup<0>,
     SetMeshBindGroup<1>,
-    SetUniformsBindGroup<2>,
     DrawMeshInstanced,
 );
 
@@ -371,22 +311,3 @@ impl EntityRenderCommand for DrawMeshInstanced {
         RenderCommandResult::Success
     }
 }
-
-struct SetUniformsBindGroup<const I: usize>;
-
-impl<const I: usize> EntityRenderCommand for SetUniformsBindGroup<I> {
-    type Param = SQuery<Read<UniformMeta>>;
-
-    fn render<'w>(
-        _view: Entity,
-        item: Entity,
-        uniforms_query: SystemParamItem<'w, '_, Self::Param>,
-        pass: &mut TrackedRenderPass<'w>,
-    ) -> RenderCommandResult {
-        if let Ok(uniforms_meta) = uniforms_query.get_inner(item) {
-            pass.set_bind_group(I, &uniforms_meta.bind_group, &[]);
-        };
-
-        RenderCommandResult::Success
-    }
-}
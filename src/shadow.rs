@@ -0,0 +1,985 @@
+use crate::{
+    cached_bind_group, CustomMaterial, ExtractedLights, InstanceBuffer, RenderMaterialInstance,
+};
+use std::num::NonZeroU32;
+
+use bevy::{
+    pbr::MeshPipelineKey,
+    prelude::*,
+    render::{
+        extract_resource::{ExtractResource, ExtractResourcePlugin},
+        mesh::{GpuBufferInfo, MeshVertexBufferLayout},
+        render_asset::RenderAssets,
+        render_graph::{Node, NodeRunError, RenderGraph, RenderGraphContext},
+        render_phase::TrackedRenderPass,
+        render_resource::{
+            AddressMode, BindGroup, BindGroupDescriptor, BindGroupEntry, BindGroupLayout,
+            BindGroupLayoutDescriptor, BindGroupLayoutEntry, BindingType, BufferBindingType,
+            BufferId, CachedRenderPipelineId, CompareFunction, DepthBiasState, DepthStencilState,
+            DynamicUniformBuffer, Extent3d, FilterMode, FragmentState, FrontFace, LoadOp,
+            MultisampleState, Operations, PipelineCache, PolygonMode, PrimitiveState,
+            RenderPassDepthStencilAttachment, RenderPassDescriptor, RenderPipelineDescriptor,
+            Sampler, SamplerDescriptor, ShaderStages, ShaderType, SpecializedMeshPipeline,
+            SpecializedMeshPipelineError, SpecializedMeshPipelines, StencilState,
+            TextureDescriptor, TextureDimension, TextureFormat, TextureId, TextureUsages,
+            TextureView, TextureViewDescriptor, TextureViewDimension, VertexAttribute,
+            VertexBufferLayout, VertexFormat, VertexState, VertexStepMode, WgpuAdapterInfo,
+        },
+        renderer::{RenderContext, RenderDevice, RenderQueue},
+        settings::Backends,
+        texture::TextureCache,
+        RenderApp, RenderStage,
+    },
+};
+
+pub const SHADOW_PASS: &str = "custom_shadow_pass";
+const SHADOW_FORMAT: TextureFormat = TextureFormat::Depth32Float;
+// Every shadow casting point light takes up 6 layers of the cube map array
+const MAX_POINT_LIGHT_SHADOWS: usize = 16;
+// Without cube map arrays there's a single cube map
+const MAX_POINT_LIGHT_SHADOWS_NO_CUBE_ARRAYS: usize = 1;
+const POINT_SHADOW_NEAR: f32 = 0.1;
+// Needs to be kept in sync with the custom_mesh.wgsl shader
+const MAX_SPOTLIGHT_SHADOWS: usize = 8;
+const SPOTLIGHT_SHADOW_NEAR: f32 = 0.1;
+
+/// Shadow mapping settings for a [`DirectionalLight`](crate::DirectionalLight).
+///
+/// The shadow map covers an orthographic box centered on the light's [`Transform`], so move the
+/// light to move the shadowed area around.
+#[derive(Debug, Clone)]
+pub struct DirectionalLightShadow {
+    /// Width and height of the shadow map in texels
+    pub resolution: u32,
+    /// Offset subtracted from a fragment's depth before comparing it with the shadow map, this is
+    /// what gets rid of shadow acne. It's scaled up for surfaces at a steep angle to the light.
+    pub depth_bias: f32,
+    /// Width of the PCF filter kernel in texels, 1 means no filtering. Even sizes are rounded up
+    pub filter_size: u32,
+    /// Half the width and height of the area covered by the shadow map
+    pub half_size: f32,
+    /// Half the depth of the area covered by the shadow map
+    pub half_depth: f32,
+}
+
+impl Default for DirectionalLightShadow {
+    fn default() -> Self {
+        Self {
+            resolution: 2048,
+            depth_bias: 0.005,
+            filter_size: 3,
+            half_size: 10.0,
+            half_depth: 20.0,
+        }
+    }
+}
+
+/// Shadow mapping settings shared by every point light that
+/// [casts shadows](crate::PointLightInstance::casts_shadows)
+#[derive(ExtractResource, Debug, Clone)]
+pub struct PointLightShadowSettings {
+    /// Width and height of each cube map face in texels
+    pub resolution: u32,
+    /// Offset subtracted from a fragment's distance to the light, relative to `far`
+    pub depth_bias: f32,
+    /// Nothing further away from the light than this casts a shadow
+    pub far: f32,
+}
+
+impl Default for PointLightShadowSettings {
+    fn default() -> Self {
+        Self {
+            resolution: 1024,
+            depth_bias: 0.005,
+            far: 25.0,
+        }
+    }
+}
+
+/// Shadow mapping settings shared by every spotlight that
+/// [casts shadows](crate::Spotlight::casts_shadows)
+#[derive(ExtractResource, Debug, Clone)]
+pub struct SpotlightShadowSettings {
+    /// Width and height of each shadow map in texels
+    pub resolution: u32,
+    /// Offset subtracted from a fragment's depth before comparing it with the shadow map
+    pub depth_bias: f32,
+    /// Radius in texels of the area sampled around each fragment, larger values give softer
+    /// shadow edges
+    pub softness: f32,
+    /// Nothing further away from the light than this casts a shadow
+    pub far: f32,
+}
+
+impl Default for SpotlightShadowSettings {
+    fn default() -> Self {
+        Self {
+            resolution: 1024,
+            depth_bias: 0.0005,
+            softness: 1.5,
+            far: 50.0,
+        }
+    }
+}
+
+pub struct ShadowPlugin;
+
+/// Whether cube map array textures can be sampled, which the GL backend (i.e. WebGL2) can't. Then
+/// only the first shadow casting point light gets a shadow, in a plain cube map, and pipelines
+/// sampling it need the `NO_CUBE_ARRAY_TEXTURES_SUPPORT` shader def.
+pub fn cube_array_textures_supported(adapter_info: &WgpuAdapterInfo) -> bool {
+    Backends::from(adapter_info.backend) != Backends::GL
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, SystemLabel)]
+pub enum ShadowSystems {
+    Prepare,
+}
+
+impl Plugin for ShadowPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<PointLightShadowSettings>()
+            .init_resource::<SpotlightShadowSettings>()
+            .add_plugin(ExtractResourcePlugin::<PointLightShadowSettings>::default())
+            .add_plugin(ExtractResourcePlugin::<SpotlightShadowSettings>::default());
+
+        let render_app = app.sub_app_mut(RenderApp);
+        render_app
+            .init_resource::<ShadowPipeline>()
+            .init_resource::<SpecializedMeshPipelines<ShadowPipeline>>()
+            .init_resource::<ShadowPassViews>()
+            .init_resource::<ShadowPassUniforms>()
+            .init_resource::<DirectionalShadowMap>()
+            .init_resource::<PointShadowMaps>()
+            .init_resource::<SpotlightShadowMaps>()
+            .add_system_to_stage(
+                RenderStage::Prepare,
+                prepare_directional_shadow.label(ShadowSystems::Prepare),
+            )
+            .add_system_to_stage(
+                RenderStage::Prepare,
+                // The directional shadow clears the shadow pass views for the frame
+                prepare_point_light_shadows
+                    .label(ShadowSystems::Prepare)
+                    .after(prepare_directional_shadow),
+            )
+            .add_system_to_stage(
+                RenderStage::Prepare,
+                prepare_spotlight_shadows
+                    .label(ShadowSystems::Prepare)
+                    .after(prepare_directional_shadow),
+            )
+            .add_system_to_stage(
+                RenderStage::Prepare,
+                prepare_shadow_pass_uniforms
+                    .after(prepare_point_light_shadows)
+                    .after(prepare_spotlight_shadows),
+            )
+            .add_system_to_stage(RenderStage::Queue, queue_shadow_casters);
+
+        let shadow_pass_node = ShadowPassNode::new(&mut render_app.world);
+        let mut graph = render_app.world.resource_mut::<RenderGraph>();
+        graph.add_node(SHADOW_PASS, shadow_pass_node);
+        graph
+            .add_node_edge(SHADOW_PASS, bevy::render::main_graph::node::CAMERA_DRIVER)
+            .unwrap();
+    }
+}
+
+/// Everything the custom material needs to sample the directional light shadow map
+#[derive(Default)]
+pub struct DirectionalShadowMap {
+    pub texture_view: Option<TextureView>,
+    pub uniform: DirectionalShadowUniform,
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, ShaderType)]
+#[repr(C)]
+pub struct DirectionalShadowUniform {
+    light_space: Mat4,
+    depth_bias: f32,
+    filter_size: u32,
+    light_index: u32,
+    enabled: u32,
+}
+
+/// Everything the custom material needs to sample the point light cube shadow maps, in a cube map
+/// array or a single cube map depending on [`cube_array_textures_supported`]
+#[derive(Default)]
+pub struct PointShadowMaps {
+    pub texture_view: Option<TextureView>,
+    // The view is kept around for as long as the texture cache hands out the same texture
+    texture_id: Option<TextureId>,
+    pub uniform: PointShadowUniform,
+    /// Layer of the cube map array for each of the extracted point lights, or -1 if it doesn't
+    /// cast shadows
+    pub shadow_indices: Vec<i32>,
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, ShaderType)]
+#[repr(C)]
+pub struct PointShadowUniform {
+    depth_bias: f32,
+    far: f32,
+}
+
+/// Everything the custom material needs to sample the spotlight shadow maps
+#[derive(Default)]
+pub struct SpotlightShadowMaps {
+    pub texture_view: Option<TextureView>,
+    texture_id: Option<TextureId>,
+    pub uniform: SpotlightShadowUniform,
+    /// Layer of the shadow map array for each of the extracted spotlights, or -1 if it doesn't
+    /// cast shadows
+    pub shadow_indices: Vec<i32>,
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, ShaderType)]
+#[repr(C)]
+pub struct SpotlightShadowUniform {
+    light_spaces: [Mat4; MAX_SPOTLIGHT_SHADOWS],
+    depth_bias: f32,
+    softness: f32,
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, ShaderType)]
+#[repr(C)]
+struct ShadowPassUniform {
+    light_space: Mat4,
+    // Only used when rendering linear depth for point lights
+    light_position: Vec3,
+    far: f32,
+}
+
+/// A single depth only render of the shadow casters from a light's point of view
+pub struct ShadowPassView {
+    label: &'static str,
+    depth_view: TextureView,
+    /// Dynamic offset of the pass in [`ShadowPassUniforms`]
+    uniform_offset: u32,
+    /// Write the distance to the light instead of the projected depth
+    linear_depth: bool,
+}
+
+#[derive(Default, Deref, DerefMut)]
+pub struct ShadowPassViews(Vec<ShadowPassView>);
+
+/// The uniforms of every shadow pass in a single buffer, bound with a dynamic offset per pass
+#[derive(Default)]
+pub struct ShadowPassUniforms {
+    buffer: DynamicUniformBuffer<ShadowPassUniform>,
+    // This frame's uniforms, and the ones currently on the GPU
+    values: Vec<ShadowPassUniform>,
+    written: Vec<ShadowPassUniform>,
+    bind_group: Option<(BufferId, BindGroup)>,
+}
+
+impl ShadowPassUniforms {
+    fn clear(&mut self) {
+        self.buffer.clear();
+        self.values.clear();
+    }
+
+    /// Adds the uniform of a shadow pass, returning its dynamic offset
+    fn push(&mut self, uniform: ShadowPassUniform) -> u32 {
+        self.values.push(uniform);
+        self.buffer.push(uniform)
+    }
+}
+
+/// The shadow pipelines specialized for the mesh of a shadow casting entity
+#[derive(Component)]
+pub struct ShadowCasterPipeline {
+    depth: CachedRenderPipelineId,
+    linear_depth: CachedRenderPipelineId,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ShadowPipelineKey {
+    mesh_key: MeshPipelineKey,
+    linear_depth: bool,
+}
+
+struct CubeMapFace {
+    target: Vec3,
+    up: Vec3,
+    label: &'static str,
+}
+
+// Cube map lookups are left handed, unlike the rest of bevy. Rendering every face looking the
+// opposite way and sampling with the fragment to light direction makes up for it.
+// see https://www.khronos.org/opengl/wiki/Cubemap_Texture
+const CUBE_MAP_FACES: [CubeMapFace; 6] = [
+    CubeMapFace {
+        target: Vec3::NEG_X,
+        up: Vec3::NEG_Y,
+        label: "point_shadow_pass_pos_x",
+    },
+    CubeMapFace {
+        target: Vec3::X,
+        up: Vec3::NEG_Y,
+        label: "point_shadow_pass_neg_x",
+    },
+    CubeMapFace {
+        target: Vec3::NEG_Y,
+        up: Vec3::Z,
+        label: "point_shadow_pass_pos_y",
+    },
+    CubeMapFace {
+        target: Vec3::Y,
+        up: Vec3::NEG_Z,
+        label: "point_shadow_pass_neg_y",
+    },
+    CubeMapFace {
+        target: Vec3::NEG_Z,
+        up: Vec3::NEG_Y,
+        label: "point_shadow_pass_pos_z",
+    },
+    CubeMapFace {
+        target: Vec3::Z,
+        up: Vec3::NEG_Y,
+        label: "point_shadow_pass_neg_z",
+    },
+];
+
+fn prepare_directional_shadow(
+    lights: Res<ExtractedLights>,
+    render_device: Res<RenderDevice>,
+    mut texture_cache: ResMut<TextureCache>,
+    mut shadow_pass_views: ResMut<ShadowPassViews>,
+    mut shadow_pass_uniforms: ResMut<ShadowPassUniforms>,
+    mut dir_shadow_map: ResMut<DirectionalShadowMap>,
+) {
+    shadow_pass_views.clear();
+    shadow_pass_uniforms.clear();
+
+    // Only the first shadow casting directional light gets a shadow map
+    let shadow_caster =
+        lights
+            .directional_lights
+            .iter()
+            .enumerate()
+            .find_map(|(i, (light, transform))| {
+                light
+                    .shadow
+                    .as_ref()
+                    .map(|shadow| (i as u32, shadow, transform))
+            });
+
+    let (light_index, shadow, transform) = match shadow_caster {
+        Some(shadow_caster) => shadow_caster,
+        None => {
+            // The shadow map is still part of the custom material bind group so bind a tiny one
+            let texture_view = shadow_texture(&render_device, &mut texture_cache, 1);
+            *dir_shadow_map = DirectionalShadowMap {
+                texture_view: Some(texture_view),
+                uniform: DirectionalShadowUniform::default(),
+            };
+            return;
+        }
+    };
+
+    let texture_view = shadow_texture(&render_device, &mut texture_cache, shadow.resolution);
+    let light_space = directional_light_space(transform, shadow);
+
+    let uniform_offset = shadow_pass_uniforms.push(ShadowPassUniform {
+        light_space,
+        ..default()
+    });
+    shadow_pass_views.push(ShadowPassView {
+        label: "directional_shadow_pass",
+        depth_view: texture_view.clone(),
+        uniform_offset,
+        linear_depth: false,
+    });
+
+    *dir_shadow_map = DirectionalShadowMap {
+        texture_view: Some(texture_view),
+        uniform: DirectionalShadowUniform {
+            light_space,
+            depth_bias: shadow.depth_bias,
+            filter_size: shadow.filter_size.max(1),
+            light_index,
+            enabled: 1,
+        },
+    };
+}
+
+#[allow(clippy::too_many_arguments)]
+fn prepare_point_light_shadows(
+    lights: Res<ExtractedLights>,
+    settings: Res<PointLightShadowSettings>,
+    render_device: Res<RenderDevice>,
+    adapter_info: Res<WgpuAdapterInfo>,
+    mut texture_cache: ResMut<TextureCache>,
+    mut shadow_pass_views: ResMut<ShadowPassViews>,
+    mut shadow_pass_uniforms: ResMut<ShadowPassUniforms>,
+    mut point_shadow_maps: ResMut<PointShadowMaps>,
+    mut last_caster_count: Local<usize>,
+) {
+    let cube_arrays = cube_array_textures_supported(&adapter_info);
+    let max_shadows = if cube_arrays {
+        MAX_POINT_LIGHT_SHADOWS
+    } else {
+        MAX_POINT_LIGHT_SHADOWS_NO_CUBE_ARRAYS
+    };
+    let mut shadow_count = 0;
+    point_shadow_maps.shadow_indices = lights
+        .point_lights
+        .iter()
+        .map(|(light, _)| {
+            if !light.casts_shadows {
+                return -1;
+            }
+            if shadow_count == max_shadows {
+                return -1;
+            }
+            shadow_count += 1;
+            shadow_count as i32 - 1
+        })
+        .collect();
+
+    // Only warn once, until the number of shadow casting lights changes
+    let caster_count = lights
+        .point_lights
+        .iter()
+        .filter(|(light, _)| light.casts_shadows)
+        .count();
+    if caster_count > max_shadows && caster_count != *last_caster_count {
+        warn!("Only {} point lights can cast shadows", max_shadows);
+    }
+    *last_caster_count = caster_count;
+
+    // The cube map array is still part of the custom material bind group when there aren't any
+    // shadow casting point lights, so always allocate at least one tiny cube
+    let resolution = if shadow_count == 0 {
+        1
+    } else {
+        settings.resolution
+    };
+    let texture = texture_cache.get(
+        &render_device,
+        TextureDescriptor {
+            label: Some("point_light_shadow_maps"),
+            size: Extent3d {
+                width: resolution,
+                height: resolution,
+                depth_or_array_layers: 6 * shadow_count.max(1) as u32,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: SHADOW_FORMAT,
+            usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING,
+        },
+    );
+
+    let projection = Mat4::perspective_rh(
+        std::f32::consts::FRAC_PI_2,
+        1.0,
+        POINT_SHADOW_NEAR,
+        settings.far,
+    );
+    let shadow_casters = lights
+        .point_lights
+        .iter()
+        .zip(point_shadow_maps.shadow_indices.iter())
+        .filter(|(_, shadow_index)| **shadow_index >= 0);
+    for ((_, transform), shadow_index) in shadow_casters {
+        let light_position = transform.translation();
+        for (face_index, face) in CUBE_MAP_FACES.iter().enumerate() {
+            let view = Transform::from_translation(light_position)
+                .looking_at(light_position + face.target, face.up)
+                .compute_matrix()
+                .inverse();
+            let depth_view = texture.texture.create_view(&TextureViewDescriptor {
+                label: Some(face.label),
+                format: None,
+                dimension: Some(TextureViewDimension::D2),
+                aspect: default(),
+                base_mip_level: 0,
+                mip_level_count: None,
+                base_array_layer: (*shadow_index as usize * 6 + face_index) as u32,
+                array_layer_count: NonZeroU32::new(1),
+            });
+            let uniform_offset = shadow_pass_uniforms.push(ShadowPassUniform {
+                light_space: projection * view,
+                light_position,
+                far: settings.far,
+            });
+            shadow_pass_views.push(ShadowPassView {
+                label: face.label,
+                depth_view,
+                uniform_offset,
+                linear_depth: true,
+            });
+        }
+    }
+
+    if point_shadow_maps.texture_id != Some(texture.texture.id()) {
+        point_shadow_maps.texture_id = Some(texture.texture.id());
+        point_shadow_maps.texture_view =
+            Some(texture.texture.create_view(&TextureViewDescriptor {
+                label: Some("point_light_shadow_maps_view"),
+                dimension: Some(if cube_arrays {
+                    TextureViewDimension::CubeArray
+                } else {
+                    TextureViewDimension::Cube
+                }),
+                ..default()
+            }));
+    }
+    point_shadow_maps.uniform = PointShadowUniform {
+        depth_bias: settings.depth_bias,
+        far: settings.far,
+    };
+}
+
+#[allow(clippy::too_many_arguments)]
+fn prepare_spotlight_shadows(
+    lights: Res<ExtractedLights>,
+    settings: Res<SpotlightShadowSettings>,
+    render_device: Res<RenderDevice>,
+    mut texture_cache: ResMut<TextureCache>,
+    mut shadow_pass_views: ResMut<ShadowPassViews>,
+    mut shadow_pass_uniforms: ResMut<ShadowPassUniforms>,
+    mut spotlight_shadow_maps: ResMut<SpotlightShadowMaps>,
+    mut last_caster_count: Local<usize>,
+) {
+    let mut shadow_count = 0;
+    spotlight_shadow_maps.shadow_indices = lights
+        .spotlights
+        .iter()
+        .map(|(light, _)| {
+            if !light.casts_shadows {
+                return -1;
+            }
+            if shadow_count == MAX_SPOTLIGHT_SHADOWS {
+                return -1;
+            }
+            shadow_count += 1;
+            shadow_count as i32 - 1
+        })
+        .collect();
+
+    // Only warn once, until the number of shadow casting lights changes
+    let caster_count = lights
+        .spotlights
+        .iter()
+        .filter(|(light, _)| light.casts_shadows)
+        .count();
+    if caster_count > MAX_SPOTLIGHT_SHADOWS && caster_count != *last_caster_count {
+        warn!("Only {} spotlights can cast shadows", MAX_SPOTLIGHT_SHADOWS);
+    }
+    *last_caster_count = caster_count;
+
+    // Always allocate at least one tiny layer, the array is part of the custom material bind group
+    let resolution = if shadow_count == 0 {
+        1
+    } else {
+        settings.resolution
+    };
+    let texture = texture_cache.get(
+        &render_device,
+        TextureDescriptor {
+            label: Some("spotlight_shadow_maps"),
+            size: Extent3d {
+                width: resolution,
+                height: resolution,
+                depth_or_array_layers: shadow_count.max(1) as u32,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: SHADOW_FORMAT,
+            usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING,
+        },
+    );
+
+    let mut light_spaces = [Mat4::IDENTITY; MAX_SPOTLIGHT_SHADOWS];
+    let shadow_casters = lights
+        .spotlights
+        .iter()
+        .zip(spotlight_shadow_maps.shadow_indices.iter())
+        .filter(|(_, shadow_index)| **shadow_index >= 0);
+    for ((light, transform), shadow_index) in shadow_casters {
+        // The frustum only has to cover the outer edge of the cone
+        let projection = Mat4::perspective_rh(
+            (light.outer_cutoff * 2.0).to_radians(),
+            1.0,
+            SPOTLIGHT_SHADOW_NEAR,
+            settings.far,
+        );
+        let light_space = projection * light_view(transform);
+        light_spaces[*shadow_index as usize] = light_space;
+
+        let depth_view = texture.texture.create_view(&TextureViewDescriptor {
+            label: Some("spotlight_shadow_map_view"),
+            format: None,
+            dimension: Some(TextureViewDimension::D2),
+            aspect: default(),
+            base_mip_level: 0,
+            mip_level_count: None,
+            base_array_layer: *shadow_index as u32,
+            array_layer_count: NonZeroU32::new(1),
+        });
+        let uniform_offset = shadow_pass_uniforms.push(ShadowPassUniform {
+            light_space,
+            ..default()
+        });
+        shadow_pass_views.push(ShadowPassView {
+            label: "spotlight_shadow_pass",
+            depth_view,
+            uniform_offset,
+            linear_depth: false,
+        });
+    }
+
+    if spotlight_shadow_maps.texture_id != Some(texture.texture.id()) {
+        spotlight_shadow_maps.texture_id = Some(texture.texture.id());
+        spotlight_shadow_maps.texture_view =
+            Some(texture.texture.create_view(&TextureViewDescriptor {
+                label: Some("spotlight_shadow_maps_view"),
+                dimension: Some(TextureViewDimension::D2Array),
+                ..default()
+            }));
+    }
+    spotlight_shadow_maps.uniform = SpotlightShadowUniform {
+        light_spaces,
+        depth_bias: settings.depth_bias,
+        softness: settings.softness,
+    };
+}
+
+/// Uploads the shadow pass uniforms once every pass of the frame has been added
+fn prepare_shadow_pass_uniforms(
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    pipeline: Res<ShadowPipeline>,
+    mut shadow_pass_uniforms: ResMut<ShadowPassUniforms>,
+) {
+    let shadow_pass_uniforms = &mut *shadow_pass_uniforms;
+    if shadow_pass_uniforms.values.is_empty() {
+        return;
+    }
+    if shadow_pass_uniforms.buffer.buffer().is_none()
+        || shadow_pass_uniforms.values != shadow_pass_uniforms.written
+    {
+        shadow_pass_uniforms
+            .buffer
+            .write_buffer(&render_device, &render_queue);
+        shadow_pass_uniforms.written = shadow_pass_uniforms.values.clone();
+    }
+
+    let binding = shadow_pass_uniforms.buffer.binding().unwrap();
+    let buffer_id = shadow_pass_uniforms.buffer.buffer().unwrap().id();
+    cached_bind_group(&mut shadow_pass_uniforms.bind_group, buffer_id, || {
+        render_device.create_bind_group(&BindGroupDescriptor {
+            label: Some("shadow pass bind group"),
+            layout: &pipeline.bind_group_layout,
+            entries: &[BindGroupEntry {
+                binding: 0,
+                resource: binding,
+            }],
+        })
+    });
+}
+
+/// Builds the view matrix of a light looking along its forward direction
+fn light_view(transform: &GlobalTransform) -> Mat4 {
+    let position = transform.translation();
+    let direction = transform.forward();
+    // look_at needs an up vector that isn't parallel to the direction
+    let up = if direction.abs().abs_diff_eq(Vec3::Y, 0.01) {
+        Vec3::X
+    } else {
+        Vec3::Y
+    };
+    Mat4::look_at_rh(position, position + direction, up)
+}
+
+/// Builds the matrix taking world positions into the directional light's clip space
+fn directional_light_space(transform: &GlobalTransform, shadow: &DirectionalLightShadow) -> Mat4 {
+    let proj = Mat4::orthographic_rh(
+        -shadow.half_size,
+        shadow.half_size,
+        -shadow.half_size,
+        shadow.half_size,
+        -shadow.half_depth,
+        shadow.half_depth,
+    );
+    proj * light_view(transform)
+}
+
+fn shadow_texture(
+    render_device: &RenderDevice,
+    texture_cache: &mut TextureCache,
+    resolution: u32,
+) -> TextureView {
+    texture_cache
+        .get(
+            render_device,
+            TextureDescriptor {
+                label: Some("directional_shadow_map"),
+                size: Extent3d {
+                    width: resolution,
+                    height: resolution,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: TextureDimension::D2,
+                format: SHADOW_FORMAT,
+                usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING,
+            },
+        )
+        .default_view
+}
+
+#[allow(clippy::too_many_arguments)]
+fn queue_shadow_casters(
+    mut commands: Commands,
+    shadow_pipeline: Res<ShadowPipeline>,
+    mut pipelines: ResMut<SpecializedMeshPipelines<ShadowPipeline>>,
+    mut pipeline_cache: ResMut<PipelineCache>,
+    meshes: Res<RenderAssets<Mesh>>,
+    shadow_casters: Query<(Entity, &Handle<Mesh>), With<CustomMaterial>>,
+) {
+    for (entity, mesh_handle) in &shadow_casters {
+        if let Some(mesh) = meshes.get(mesh_handle) {
+            let mesh_key = MeshPipelineKey::from_primitive_topology(mesh.primitive_topology);
+            let mut specialize = |linear_depth| {
+                pipelines
+                    .specialize(
+                        &mut pipeline_cache,
+                        &shadow_pipeline,
+                        ShadowPipelineKey {
+                            mesh_key,
+                            linear_depth,
+                        },
+                        &mesh.layout,
+                    )
+                    .unwrap()
+            };
+            commands.entity(entity).insert(ShadowCasterPipeline {
+                depth: specialize(false),
+                linear_depth: specialize(true),
+            });
+        }
+    }
+}
+
+pub struct ShadowPipeline {
+    shader: Handle<Shader>,
+    bind_group_layout: BindGroupLayout,
+    /// Comparison sampler used by the materials to read the shadow maps
+    pub sampler: Sampler,
+}
+
+impl FromWorld for ShadowPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let asset_server = world.resource::<AssetServer>();
+        let render_device = world.resource::<RenderDevice>();
+        let shader = asset_server.load("shaders/shadow_depth.wgsl");
+
+        let bind_group_layout =
+            render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                label: Some("Shadow pass uniforms"),
+                entries: &[BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStages::VERTEX | ShaderStages::FRAGMENT,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: true,
+                        min_binding_size: Some(ShadowPassUniform::min_size()),
+                    },
+                    count: None,
+                }],
+            });
+
+        let sampler = render_device.create_sampler(&SamplerDescriptor {
+            label: Some("shadow map sampler"),
+            address_mode_u: AddressMode::ClampToEdge,
+            address_mode_v: AddressMode::ClampToEdge,
+            address_mode_w: AddressMode::ClampToEdge,
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            mipmap_filter: FilterMode::Nearest,
+            compare: Some(CompareFunction::LessEqual),
+            ..default()
+        });
+
+        ShadowPipeline {
+            shader,
+            bind_group_layout,
+            sampler,
+        }
+    }
+}
+
+impl SpecializedMeshPipeline for ShadowPipeline {
+    type Key = ShadowPipelineKey;
+
+    fn specialize(
+        &self,
+        key: Self::Key,
+        layout: &MeshVertexBufferLayout,
+    ) -> Result<RenderPipelineDescriptor, SpecializedMeshPipelineError> {
+        let vertex_buffer_layout =
+            layout.get_layout(&[Mesh::ATTRIBUTE_POSITION.at_shader_location(0)])?;
+        // Only the model matrix of the instance data is needed to render depth
+        let instance_buffer_layout = VertexBufferLayout {
+            array_stride: (std::mem::size_of::<RenderMaterialInstance>() as u64),
+            step_mode: VertexStepMode::Instance,
+            attributes: (0..4)
+                .map(|i| VertexAttribute {
+                    format: VertexFormat::Float32x4,
+                    offset: VertexFormat::Float32x4.size() * i,
+                    shader_location: 3 + i as u32,
+                })
+                .collect(),
+        };
+
+        // Point lights write the distance to the light as depth, which needs a fragment shader
+        let mut shader_defs = Vec::new();
+        let fragment = if key.linear_depth {
+            shader_defs.push(String::from("LINEAR_DEPTH"));
+            Some(FragmentState {
+                shader: self.shader.clone(),
+                shader_defs: shader_defs.clone(),
+                entry_point: "fragment".into(),
+                targets: vec![],
+            })
+        } else {
+            None
+        };
+
+        Ok(RenderPipelineDescriptor {
+            label: Some("Shadow pipeline descriptor".into()),
+            layout: Some(vec![self.bind_group_layout.clone()]),
+            vertex: VertexState {
+                shader: self.shader.clone(),
+                shader_defs,
+                entry_point: "vertex".into(),
+                buffers: vec![vertex_buffer_layout, instance_buffer_layout],
+            },
+            primitive: PrimitiveState {
+                front_face: FrontFace::Cw,
+                cull_mode: None,
+                unclipped_depth: false,
+                polygon_mode: PolygonMode::Fill,
+                conservative: false,
+                topology: key.mesh_key.primitive_topology(),
+                strip_index_format: None,
+            },
+            depth_stencil: Some(DepthStencilState {
+                format: SHADOW_FORMAT,
+                depth_write_enabled: true,
+                depth_compare: CompareFunction::Less,
+                stencil: StencilState::default(),
+                bias: DepthBiasState::default(),
+            }),
+            multisample: MultisampleState::default(),
+            fragment,
+        })
+    }
+}
+
+/// Renders every [`ShadowPassView`] before the cameras get drawn
+pub struct ShadowPassNode {
+    shadow_casters: QueryState<(
+        &'static Handle<Mesh>,
+        &'static InstanceBuffer,
+        &'static ShadowCasterPipeline,
+    )>,
+}
+
+impl ShadowPassNode {
+    pub fn new(world: &mut World) -> Self {
+        Self {
+            shadow_casters: QueryState::new(world),
+        }
+    }
+}
+
+impl Node for ShadowPassNode {
+    fn update(&mut self, world: &mut World) {
+        self.shadow_casters.update_archetypes(world);
+    }
+
+    fn run(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        let shadow_pass_views = world.resource::<ShadowPassViews>();
+        let shadow_pass_bind_group = match &world.resource::<ShadowPassUniforms>().bind_group {
+            Some((_, bind_group)) => bind_group,
+            None => return Ok(()),
+        };
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let meshes = world.resource::<RenderAssets<Mesh>>();
+
+        for shadow_pass_view in shadow_pass_views.iter() {
+            let pass_descriptor = RenderPassDescriptor {
+                label: Some(shadow_pass_view.label),
+                color_attachments: &[],
+                depth_stencil_attachment: Some(RenderPassDepthStencilAttachment {
+                    view: &shadow_pass_view.depth_view,
+                    depth_ops: Some(Operations {
+                        load: LoadOp::Clear(1.0),
+                        store: true,
+                    }),
+                    stencil_ops: None,
+                }),
+            };
+            let render_pass = render_context
+                .command_encoder
+                .begin_render_pass(&pass_descriptor);
+            let mut pass = TrackedRenderPass::new(render_pass);
+            pass.set_bind_group(
+                0,
+                shadow_pass_bind_group,
+                &[shadow_pass_view.uniform_offset],
+            );
+
+            for (mesh_handle, instance_buffer, caster_pipeline) in
+                self.shadow_casters.iter_manual(world)
+            {
+                let pipeline_id = if shadow_pass_view.linear_depth {
+                    caster_pipeline.linear_depth
+                } else {
+                    caster_pipeline.depth
+                };
+                let (pipeline, gpu_mesh) = match (
+                    pipeline_cache.get_render_pipeline(pipeline_id),
+                    meshes.get(mesh_handle),
+                ) {
+                    (Some(pipeline), Some(gpu_mesh)) => (pipeline, gpu_mesh),
+                    _ => continue,
+                };
+
+                pass.set_render_pipeline(pipeline);
+                pass.set_vertex_buffer(0, gpu_mesh.vertex_buffer.slice(..));
+                pass.set_vertex_buffer(1, instance_buffer.buffer.slice(..));
+
+                match &gpu_mesh.buffer_info {
+                    GpuBufferInfo::Indexed {
+                        buffer,
+                        index_format,
+                        count,
+                    } => {
+                        pass.set_index_buffer(buffer.slice(..), 0, *index_format);
+                        pass.draw_indexed(0..*count, 0, 0..instance_buffer.length as u32);
+                    }
+                    GpuBufferInfo::NonIndexed { vertex_count } => {
+                        pass.draw(0..*vertex_count, 0..instance_buffer.length as u32);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,685 @@
+use crate::{
+    cached_bind_group, cube_array_textures_supported, DirectionalLight, DirectionalShadowMap,
+    DirectionalShadowUniform, PointLightInstance, PointShadowMaps, PointShadowUniform,
+    ShadowPipeline, ShadowSystems, Spotlight, SpotlightShadowMaps, SpotlightShadowUniform,
+};
+use bevy::{
+    ecs::system::{
+        lifetimeless::{Read, SQuery},
+        SystemParamItem,
+    },
+    prelude::*,
+    reflect::TypeUuid,
+    render::{
+        render_phase::{EntityRenderCommand, RenderCommandResult, TrackedRenderPass},
+        render_resource::{
+            encase::internal::WriteInto, BindGroup, BindGroupDescriptor, BindGroupEntry,
+            BindGroupLayout, BindGroupLayoutDescriptor, BindGroupLayoutEntry, BindingResource,
+            BindingType, BufferBindingType, BufferId, SamplerBindingType, ShaderStages, ShaderType,
+            StorageBuffer, TextureSampleType, TextureViewDimension, TextureViewId, UniformBuffer,
+            WgpuAdapterInfo,
+        },
+        renderer::{RenderDevice, RenderQueue},
+        view::ExtractedView,
+        Extract, RenderApp, RenderStage,
+    },
+};
+
+// The largest uniform binding WebGL2 is guaranteed to support
+const MAX_UNIFORM_BUFFER_BINDING_SIZE: usize = 16384;
+// Only used when storage buffers aren't supported, in which case the point lights are uploaded
+// as a fixed size uniform array. As many as fit in the smallest uniform binding, rounded down to
+// a power of two for some margin. The shaders get it from `custom_view::limits`.
+const MAX_UNIFORM_POINT_LIGHTS: usize =
+    (MAX_UNIFORM_BUFFER_BINDING_SIZE / std::mem::size_of::<PointLightSettings>() + 1)
+        .next_power_of_two()
+        / 2;
+// These array sizes need to be kept in sync with the custom_mesh.wgsl shader
+const MAX_DIRECTIONAL_LIGHTS: usize = 4;
+const MAX_SPOTLIGHTS: usize = 16;
+
+/// Generated shader module holding the light limits that depend on the platform, imported as
+/// `custom_view::limits`
+pub const CUSTOM_VIEW_LIMITS_HANDLE: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 6_418_302_957_114_820_511);
+
+/// Prepares the light and shadow bindings shared by every custom material pipeline.
+///
+/// Pipelines add [`CustomViewBindGroupLayout`] to their layout and bind the group with
+/// [`SetCustomViewBindGroup`].
+pub struct CustomViewBindGroupPlugin;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, SystemLabel)]
+pub enum CustomViewSystems {
+    Prepare,
+}
+
+impl Plugin for CustomViewBindGroupPlugin {
+    fn build(&self, app: &mut App) {
+        app.world.resource_mut::<Assets<Shader>>().set_untracked(
+            CUSTOM_VIEW_LIMITS_HANDLE,
+            Shader::from_wgsl(format!(
+                "#define_import_path custom_view::limits\n\n\
+                 let MAX_UNIFORM_POINT_LIGHTS: u32 = {}u;\n",
+                MAX_UNIFORM_POINT_LIGHTS
+            )),
+        );
+        app.sub_app_mut(RenderApp)
+            .init_resource::<ExtractedLights>()
+            .init_resource::<CustomViewBindGroupLayout>()
+            .init_resource::<CustomViewBuffers>()
+            .add_system_to_stage(RenderStage::Extract, extract_lights)
+            .add_system_to_stage(
+                RenderStage::Prepare,
+                prepare_custom_view_bind_groups
+                    .label(CustomViewSystems::Prepare)
+                    .after(ShadowSystems::Prepare),
+            );
+    }
+}
+
+/// Every light entity in the main world, gathered during extract
+#[derive(Default)]
+pub struct ExtractedLights {
+    pub directional_lights: Vec<(DirectionalLight, GlobalTransform)>,
+    pub point_lights: Vec<(PointLightInstance, GlobalTransform)>,
+    pub spotlights: Vec<(Spotlight, GlobalTransform)>,
+}
+
+fn extract_lights(
+    mut commands: Commands,
+    directional_lights: Extract<Query<(&DirectionalLight, &GlobalTransform)>>,
+    point_lights: Extract<Query<(&PointLightInstance, &GlobalTransform)>>,
+    spotlights: Extract<Query<(&Spotlight, &GlobalTransform)>>,
+) {
+    commands.insert_resource(ExtractedLights {
+        directional_lights: directional_lights
+            .iter()
+            .map(|(light, transform)| (light.clone(), *transform))
+            .collect(),
+        point_lights: point_lights
+            .iter()
+            .map(|(light, transform)| (*light, *transform))
+            .collect(),
+        spotlights: spotlights
+            .iter()
+            .map(|(light, transform)| (light.clone(), *transform))
+            .collect(),
+    });
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, ShaderType)]
+#[repr(C)]
+struct DirectionalLightSettings {
+    direction: Vec3,
+    ambient: Vec4,
+    diffuse: Vec4,
+    specular: Vec4,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, ShaderType)]
+#[repr(C)]
+struct PointLightSettings {
+    position: Vec3,
+    constant: f32,
+    linear: f32,
+    quadratic: f32,
+    // Layer of the cube shadow map array, -1 when the light doesn't cast shadows
+    shadow_index: i32,
+    ambient: Vec4,
+    diffuse: Vec4,
+    specular: Vec4,
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, ShaderType)]
+#[repr(C)]
+struct SpotlightSettings {
+    direction: Vec3,
+    position: Vec3,
+    cutoff: f32,
+    outer_cutoff: f32,
+    ambient: Vec4,
+    diffuse: Vec4,
+    specular: Vec4,
+    constant: f32,
+    linear: f32,
+    quadratic: f32,
+    // Layer of the shadow map array, -1 when the light doesn't cast shadows
+    shadow_index: i32,
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, ShaderType)]
+#[repr(C)]
+struct LightCounts {
+    directional_count: u32,
+    point_count: u32,
+    spot_count: u32,
+}
+
+impl Default for PointLightSettings {
+    fn default() -> Self {
+        Self {
+            position: Vec3::splat(0.0),
+            constant: 1.0,
+            linear: 0.1,
+            quadratic: 0.01,
+            shadow_index: -1,
+            ambient: Vec4::splat(0.0),
+            diffuse: Vec4::splat(0.0),
+            specular: Vec4::splat(0.0),
+        }
+    }
+}
+
+/// Uploads `value` unless the buffer already holds it
+fn write_uniform<T: ShaderType + WriteInto + PartialEq>(
+    buffer: &mut UniformBuffer<T>,
+    value: T,
+    render_device: &RenderDevice,
+    render_queue: &RenderQueue,
+) {
+    if buffer.buffer().is_none() || *buffer.get() != value {
+        buffer.set(value);
+        buffer.write_buffer(render_device, render_queue);
+    }
+}
+
+fn labelled_uniform<T: ShaderType + WriteInto>(label: &str, value: T) -> UniformBuffer<T> {
+    let mut buffer = UniformBuffer::from(value);
+    buffer.set_label(Some(label));
+    buffer
+}
+
+/// Copies `items` into a fixed size array that can be uploaded as a uniform, returning the number
+/// of items that fit. Going over the limit gets warned about once, until the number of items
+/// changes from `last_len`.
+fn to_uniform_array<T: Copy + Default, const N: usize>(
+    items: &[T],
+    name: &str,
+    last_len: &mut usize,
+) -> ([T; N], u32) {
+    if items.len() > N && items.len() != *last_len {
+        warn!(
+            "{} {} found but only {} are supported",
+            items.len(),
+            name,
+            N
+        );
+    }
+    *last_len = items.len();
+    let mut array = [T::default(); N];
+    let count = items.len().min(N);
+    array[..count].copy_from_slice(&items[..count]);
+    (array, count as u32)
+}
+
+/// Number of each kind of light last frame, to only warn about too many of them once
+#[derive(Default)]
+struct LastLightCounts {
+    directional: usize,
+    point: usize,
+    spot: usize,
+}
+
+/// GPU buffers of the light and shadow uniforms
+pub struct CustomViewBuffers {
+    directional_lights: UniformBuffer<[DirectionalLightSettings; MAX_DIRECTIONAL_LIGHTS]>,
+    point_lights: StorageBuffer<Vec<PointLightSettings>>,
+    // Only used when storage buffers aren't supported
+    uniform_point_lights: UniformBuffer<[PointLightSettings; MAX_UNIFORM_POINT_LIGHTS]>,
+    spotlights: UniformBuffer<[SpotlightSettings; MAX_SPOTLIGHTS]>,
+    light_counts: UniformBuffer<LightCounts>,
+    directional_shadow: UniformBuffer<DirectionalShadowUniform>,
+    point_shadow: UniformBuffer<PointShadowUniform>,
+    spotlight_shadow: UniformBuffer<SpotlightShadowUniform>,
+    bind_group: Option<(CustomViewBindGroupKey, BindGroup)>,
+}
+
+impl Default for CustomViewBuffers {
+    fn default() -> Self {
+        let mut point_lights = StorageBuffer::default();
+        point_lights.set_label(Some("point lights buffer"));
+        Self {
+            directional_lights: labelled_uniform("light color buffer", default()),
+            point_lights,
+            uniform_point_lights: labelled_uniform(
+                "point lights buffer",
+                [PointLightSettings::default(); MAX_UNIFORM_POINT_LIGHTS],
+            ),
+            spotlights: labelled_uniform("spot light buffer", default()),
+            light_counts: labelled_uniform("light counts buffer", default()),
+            directional_shadow: labelled_uniform("directional shadow buffer", default()),
+            point_shadow: labelled_uniform("point shadow buffer", default()),
+            spotlight_shadow: labelled_uniform("spotlight shadow buffer", default()),
+            bind_group: None,
+        }
+    }
+}
+
+/// Ids of the buffers and texture views bound by a custom view bind group, the shadow sampler
+/// never changes
+type CustomViewBindGroupKey = ([BufferId; 7], [TextureViewId; 3]);
+
+/// The light and shadow bindings of a view. The camera comes from bevy's own view uniform.
+#[derive(Component)]
+pub struct CustomViewBindGroup {
+    pub bind_group: BindGroup,
+}
+
+#[allow(clippy::too_many_arguments)]
+fn prepare_custom_view_bind_groups(
+    mut commands: Commands,
+    views: Query<Entity, With<ExtractedView>>,
+    lights: Res<ExtractedLights>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    layout: Res<CustomViewBindGroupLayout>,
+    dir_shadow_map: Res<DirectionalShadowMap>,
+    point_shadow_maps: Res<PointShadowMaps>,
+    spotlight_shadow_maps: Res<SpotlightShadowMaps>,
+    shadow_pipeline: Res<ShadowPipeline>,
+    mut buffers: ResMut<CustomViewBuffers>,
+    mut last_counts: Local<LastLightCounts>,
+) {
+    let (dir_shadow_texture_view, point_shadow_texture_view, spotlight_shadow_texture_view) = match (
+        &dir_shadow_map.texture_view,
+        &point_shadow_maps.texture_view,
+        &spotlight_shadow_maps.texture_view,
+    ) {
+        (Some(dir), Some(point), Some(spot)) => (dir, point, spot),
+        _ => return,
+    };
+    let buffers = &mut *buffers;
+
+    let spotlights = lights
+        .spotlights
+        .iter()
+        .zip(spotlight_shadow_maps.shadow_indices.iter())
+        .map(
+            |((spot_light, transform), shadow_index)| SpotlightSettings {
+                direction: transform.forward(),
+                position: transform.translation(),
+                cutoff: spot_light.cutoff.to_radians().cos(),
+                outer_cutoff: spot_light.outer_cutoff.to_radians().cos(),
+                ambient: spot_light.ambient,
+                diffuse: spot_light.diffuse,
+                specular: spot_light.specular,
+                constant: spot_light.constant,
+                linear: spot_light.linear,
+                quadratic: spot_light.quadratic,
+                shadow_index: *shadow_index,
+            },
+        )
+        .collect::<Vec<SpotlightSettings>>();
+    let (spotlights, spotlight_count) =
+        to_uniform_array::<_, MAX_SPOTLIGHTS>(&spotlights, "spotlights", &mut last_counts.spot);
+    write_uniform(
+        &mut buffers.spotlights,
+        spotlights,
+        &render_device,
+        &render_queue,
+    );
+
+    let dir_lights = lights
+        .directional_lights
+        .iter()
+        .map(|(dir_light, transform)| DirectionalLightSettings {
+            direction: transform.forward(),
+            ambient: dir_light.ambient,
+            diffuse: dir_light.diffuse,
+            specular: dir_light.specular,
+        })
+        .collect::<Vec<DirectionalLightSettings>>();
+    let (dir_lights, dir_light_count) = to_uniform_array::<_, MAX_DIRECTIONAL_LIGHTS>(
+        &dir_lights,
+        "directional lights",
+        &mut last_counts.directional,
+    );
+    write_uniform(
+        &mut buffers.directional_lights,
+        dir_lights,
+        &render_device,
+        &render_queue,
+    );
+
+    let mut point_lights = lights
+        .point_lights
+        .iter()
+        .zip(point_shadow_maps.shadow_indices.iter())
+        .map(|((instance, transform), shadow_index)| PointLightSettings {
+            position: transform.translation(),
+            constant: instance.constant,
+            linear: instance.linear,
+            quadratic: instance.quadratic,
+            shadow_index: *shadow_index,
+            ambient: instance.ambient,
+            diffuse: instance.diffuse,
+            specular: instance.specular,
+        })
+        .collect::<Vec<PointLightSettings>>();
+
+    let point_light_count = match layout.point_light_binding_type {
+        BufferBindingType::Storage { .. } => {
+            let point_light_count = point_lights.len() as u32;
+            // Bindings can't be empty so upload a single unused light when there are none
+            if point_lights.is_empty() {
+                point_lights.push(PointLightSettings::default());
+            }
+            if buffers.point_lights.buffer().is_none()
+                || *buffers.point_lights.get() != point_lights
+            {
+                buffers.point_lights.set(point_lights);
+                buffers
+                    .point_lights
+                    .write_buffer(&render_device, &render_queue);
+            }
+            point_light_count
+        }
+        BufferBindingType::Uniform => {
+            let (uniform_point_lights, point_light_count) =
+                to_uniform_array::<_, MAX_UNIFORM_POINT_LIGHTS>(
+                    &point_lights,
+                    "point lights",
+                    &mut last_counts.point,
+                );
+            write_uniform(
+                &mut buffers.uniform_point_lights,
+                uniform_point_lights,
+                &render_device,
+                &render_queue,
+            );
+            point_light_count
+        }
+    };
+
+    write_uniform(
+        &mut buffers.light_counts,
+        LightCounts {
+            directional_count: dir_light_count,
+            point_count: point_light_count,
+            spot_count: spotlight_count,
+        },
+        &render_device,
+        &render_queue,
+    );
+    write_uniform(
+        &mut buffers.directional_shadow,
+        dir_shadow_map.uniform,
+        &render_device,
+        &render_queue,
+    );
+    write_uniform(
+        &mut buffers.point_shadow,
+        point_shadow_maps.uniform,
+        &render_device,
+        &render_queue,
+    );
+    write_uniform(
+        &mut buffers.spotlight_shadow,
+        spotlight_shadow_maps.uniform,
+        &render_device,
+        &render_queue,
+    );
+
+    // Every buffer has been written to at least once above, so they all exist by now
+    let dir_light_mat_buffer = buffers.directional_lights.buffer().unwrap();
+    let point_light_mat_buffer = match layout.point_light_binding_type {
+        BufferBindingType::Storage { .. } => buffers.point_lights.buffer().unwrap(),
+        BufferBindingType::Uniform => buffers.uniform_point_lights.buffer().unwrap(),
+    };
+    let spot_light_mat_buffer = buffers.spotlights.buffer().unwrap();
+    let light_counts_buffer = buffers.light_counts.buffer().unwrap();
+    let dir_shadow_buffer = buffers.directional_shadow.buffer().unwrap();
+    let point_shadow_buffer = buffers.point_shadow.buffer().unwrap();
+    let spotlight_shadow_buffer = buffers.spotlight_shadow.buffer().unwrap();
+
+    let key = (
+        [
+            dir_light_mat_buffer.id(),
+            point_light_mat_buffer.id(),
+            spot_light_mat_buffer.id(),
+            light_counts_buffer.id(),
+            dir_shadow_buffer.id(),
+            point_shadow_buffer.id(),
+            spotlight_shadow_buffer.id(),
+        ],
+        [
+            dir_shadow_texture_view.id(),
+            point_shadow_texture_view.id(),
+            spotlight_shadow_texture_view.id(),
+        ],
+    );
+    let bind_group = cached_bind_group(&mut buffers.bind_group, key, || {
+        render_device.create_bind_group(&BindGroupDescriptor {
+            label: Some("custom view bind group"),
+            layout: &layout.layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: dir_light_mat_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: point_light_mat_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: spot_light_mat_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: light_counts_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 4,
+                    resource: BindingResource::TextureView(dir_shadow_texture_view),
+                },
+                BindGroupEntry {
+                    binding: 5,
+                    resource: BindingResource::Sampler(&shadow_pipeline.sampler),
+                },
+                BindGroupEntry {
+                    binding: 6,
+                    resource: dir_shadow_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 7,
+                    resource: BindingResource::TextureView(point_shadow_texture_view),
+                },
+                BindGroupEntry {
+                    binding: 8,
+                    resource: point_shadow_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 9,
+                    resource: BindingResource::TextureView(spotlight_shadow_texture_view),
+                },
+                BindGroupEntry {
+                    binding: 10,
+                    resource: spotlight_shadow_buffer.as_entire_binding(),
+                },
+            ],
+        })
+    });
+    for entity in &views {
+        commands.entity(entity).insert(CustomViewBindGroup {
+            bind_group: bind_group.clone(),
+        });
+    }
+}
+
+/// Layout of the bind group holding the lights and shadows of a view
+#[derive(Clone)]
+pub struct CustomViewBindGroupLayout {
+    pub layout: BindGroupLayout,
+    /// Pipelines reading the point lights need the `NO_STORAGE_BUFFERS_SUPPORT` shader def when
+    /// this is [`BufferBindingType::Uniform`]
+    pub point_light_binding_type: BufferBindingType,
+    /// Pipelines sampling the point light shadows need the `NO_CUBE_ARRAY_TEXTURES_SUPPORT` shader
+    /// def when this is [`TextureViewDimension::Cube`]
+    pub point_shadow_view_dimension: TextureViewDimension,
+}
+
+impl FromWorld for CustomViewBindGroupLayout {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+
+        // Point lights go in a runtime sized storage buffer, unless the platform doesn't support
+        // them (i.e. WebGL2) in which case we fall back to a fixed size uniform array
+        let point_light_binding_type = render_device.get_supported_read_only_binding_type(1);
+        let point_light_min_binding_size = match point_light_binding_type {
+            BufferBindingType::Storage { .. } => PointLightSettings::min_size(),
+            BufferBindingType::Uniform => {
+                <[PointLightSettings; MAX_UNIFORM_POINT_LIGHTS]>::min_size()
+            }
+        };
+        let point_shadow_view_dimension =
+            if cube_array_textures_supported(world.resource::<WgpuAdapterInfo>()) {
+                TextureViewDimension::CubeArray
+            } else {
+                TextureViewDimension::Cube
+            };
+
+        let layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("Custom view uniforms"),
+            entries: &[
+                BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: Some(
+                            <[DirectionalLightSettings; MAX_DIRECTIONAL_LIGHTS]>::min_size(),
+                        ),
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Buffer {
+                        ty: point_light_binding_type,
+                        has_dynamic_offset: false,
+                        min_binding_size: Some(point_light_min_binding_size),
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: Some(<[SpotlightSettings; MAX_SPOTLIGHTS]>::min_size()),
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: Some(LightCounts::min_size()),
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Texture {
+                        multisampled: false,
+                        sample_type: TextureSampleType::Depth,
+                        view_dimension: TextureViewDimension::D2,
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 5,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Sampler(SamplerBindingType::Comparison),
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 6,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: Some(DirectionalShadowUniform::min_size()),
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 7,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Texture {
+                        multisampled: false,
+                        sample_type: TextureSampleType::Depth,
+                        view_dimension: point_shadow_view_dimension,
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 8,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: Some(PointShadowUniform::min_size()),
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 9,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Texture {
+                        multisampled: false,
+                        sample_type: TextureSampleType::Depth,
+                        view_dimension: TextureViewDimension::D2Array,
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 10,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: Some(SpotlightShadowUniform::min_size()),
+                    },
+                    count: None,
+                },
+            ],
+        });
+
+        CustomViewBindGroupLayout {
+            layout,
+            point_light_binding_type,
+            point_shadow_view_dimension,
+        }
+    }
+}
+
+/// Binds the [`CustomViewBindGroup`] of the view being rendered
+pub struct SetCustomViewBindGroup<const I: usize>;
+
+impl<const I: usize> EntityRenderCommand for SetCustomViewBindGroup<I> {
+    type Param = SQuery<Read<CustomViewBindGroup>>;
+
+    fn render<'w>(
+        view: Entity,
+        _item: Entity,
+        view_query: SystemParamItem<'w, '_, Self::Param>,
+        pass: &mut TrackedRenderPass<'w>,
+    ) -> RenderCommandResult {
+        match view_query.get_inner(view) {
+            Ok(view_bind_group) => {
+                pass.set_bind_group(I, &view_bind_group.bind_group, &[]);
+                RenderCommandResult::Success
+            }
+            Err(_) => RenderCommandResult::Failure,
+        }
+    }
+}